@@ -0,0 +1,139 @@
+//! Stable codes for the errors scripts can run into.
+//!
+//! gart only reports free-form messages, so errors are classified by matching
+//! their message against this table. Codes never change meaning once published;
+//! UIs key localized text and help pages off them.
+
+use crate::vm_core::{Diagnostic, Fault};
+
+/// What kind of mistake an error points at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    Syntax,
+    Resolution,
+    Type,
+    Arity,
+    Native,
+    /// Runtime errors that fit nowhere else.
+    Runtime,
+}
+
+impl Category {
+    pub fn as_str(self) -> &'static str {
+        return match self {
+            Category::Syntax => "syntax",
+            Category::Resolution => "resolution",
+            Category::Type => "type",
+            Category::Arity => "arity",
+            Category::Native => "native",
+            Category::Runtime => "runtime",
+        };
+    }
+}
+
+/// Whether an error is reported by the compiler or while running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Compile,
+    Runtime,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    pub category: Category,
+    pub stage: Stage,
+    pub summary: &'static str,
+    /// Part of the message that identifies the error; empty for catch-alls.
+    fragment: &'static str,
+}
+
+const fn entry(code: &'static str, category: Category, stage: Stage, fragment: &'static str, summary: &'static str) -> ErrorCode {
+    return ErrorCode { code, category, stage, summary, fragment };
+}
+
+/// Every error code, in code order. Within a stage the first entry whose
+/// fragment the message contains wins, so catch-alls come last.
+pub static CATALOGUE: &[ErrorCode] = &[
+    entry("E0100", Category::Syntax, Stage::Compile, "", "The script could not be parsed."),
+    entry("E0101", Category::Syntax, Stage::Compile, "Expect expression.", "An expression was expected."),
+    entry("E0102", Category::Syntax, Stage::Compile, "Invalid assignment target.", "Only variables and fields can be assigned to."),
+    entry("E0103", Category::Syntax, Stage::Compile, "Expect ", "A required token is missing."),
+    entry("E0104", Category::Syntax, Stage::Compile, "Unterminated string.", "A string literal is missing its closing quote."),
+    entry("E0105", Category::Syntax, Stage::Compile, "Unexpected character.", "The script contains a character gart does not understand."),
+    entry("E0201", Category::Resolution, Stage::Compile, "Already a variable with this name", "A variable is declared twice in the same scope."),
+    entry("E0202", Category::Resolution, Stage::Compile, "in its own initializer", "A variable is read in its own initializer."),
+    entry("E0203", Category::Resolution, Stage::Compile, "Can't return from top-level code.", "`return` is used outside a function."),
+    entry("E0204", Category::Resolution, Stage::Runtime, "Undefined variable", "A variable is used before it is defined."),
+    entry("E0205", Category::Resolution, Stage::Runtime, "Undefined function", "The host called a function the script does not define."),
+    entry("E0301", Category::Type, Stage::Runtime, "Operand must be a number.", "A number was expected."),
+    entry("E0302", Category::Type, Stage::Runtime, "Operands must be", "An operator was given values of the wrong types."),
+    entry("E0303", Category::Type, Stage::Runtime, "Can only call", "A value that is not a function was called."),
+    entry("E0401", Category::Arity, Stage::Runtime, "arguments but got", "A function was called with the wrong number of arguments."),
+    entry("E0501", Category::Native, Stage::Runtime, "Native '", "A host native threw, or a value could not be converted between gart and JS."),
+    entry("E0600", Category::Runtime, Stage::Runtime, "", "The script failed while running."),
+];
+
+/// The code for a compile error.
+pub fn for_diagnostic(diagnostic: &Diagnostic) -> &'static ErrorCode {
+    return classify(Stage::Compile, &diagnostic.message);
+}
+
+/// The code for a runtime error.
+pub fn for_fault(fault: &Fault) -> &'static ErrorCode {
+    if fault.native.is_some() {
+        return code("E0501");
+    }
+    return classify(Stage::Runtime, &fault.message);
+}
+
+/// The code for a value that could not be converted outside any native, e.g.
+/// an argument the host passed to a script function.
+pub fn for_conversion() -> &'static ErrorCode {
+    return code("E0501");
+}
+
+fn classify(stage: Stage, message: &str) -> &'static ErrorCode {
+    let matching = |entry: &&ErrorCode| entry.stage == stage && !entry.fragment.is_empty() && message.contains(entry.fragment);
+    let catch_all = |entry: &&ErrorCode| entry.stage == stage && entry.fragment.is_empty();
+    return CATALOGUE.iter().find(matching)
+        .or_else(|| CATALOGUE.iter().find(catch_all))
+        .expect("every stage has a catch-all");
+}
+
+fn code(code: &str) -> &'static ErrorCode {
+    return CATALOGUE.iter().find(|entry| entry.code == code).expect("code is catalogued");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(message: &str) -> Diagnostic {
+        return Diagnostic { line: 1, start: 0, len: 1, message: message.to_owned() };
+    }
+
+    #[test]
+    fn codes_are_unique_and_ordered() {
+        for pair in CATALOGUE.windows(2) {
+            assert!(pair[0].code < pair[1].code, "{} before {}", pair[0].code, pair[1].code);
+        }
+    }
+
+    #[test]
+    fn specific_entries_win_over_general_ones() {
+        assert_eq!(for_diagnostic(&diagnostic("Expect expression.")).code, "E0101");
+        assert_eq!(for_diagnostic(&diagnostic("Expect ';' after value.")).code, "E0103");
+        assert_eq!(for_diagnostic(&diagnostic("Too many constants in one chunk.")).code, "E0100");
+    }
+
+    #[test]
+    fn runtime_errors_are_classified_by_message_or_origin() {
+        assert_eq!(for_fault(&Fault::new("Expected 2 arguments but got 1.")).category, Category::Arity);
+        assert_eq!(for_fault(&Fault::new("Undefined variable 'x'.")).category, Category::Resolution);
+        assert_eq!(for_fault(&Fault::new("Stack overflow.")).code, "E0600");
+        // Compile-time wording does not leak into runtime codes.
+        assert_eq!(for_fault(&Fault::new("Expect a list.")).code, "E0600");
+        assert_eq!(for_conversion().category, Category::Native);
+    }
+}
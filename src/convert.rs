@@ -0,0 +1,153 @@
+//! Marshalling between gart values and JS values.
+
+use std::rc::Rc;
+use gart::Value;
+use js_sys::{Array, Function, Object, Reflect};
+use wasm_bindgen::prelude::*;
+use crate::host::Host;
+use crate::marshal::{ConversionError, JsShape, JsSide, Marshal};
+use crate::vm_core::type_name;
+
+pub trait JsConvert: Sized {
+    fn try_to_js(&self, cx: &mut Marshal<Rc<Host>>) -> Result<JsValue, ConversionError>;
+    fn try_from_js(js: JsValue, cx: &mut Marshal<Rc<Host>>) -> Result<Self, ConversionError>;
+}
+
+impl JsConvert for Value {
+    fn try_to_js(&self, cx: &mut Marshal<Rc<Host>>) -> Result<JsValue, ConversionError> {
+        return cx.value_to_js(self);
+    }
+    fn try_from_js(js: JsValue, cx: &mut Marshal<Rc<Host>>) -> Result<Self, ConversionError> {
+        return cx.value_from_js(js);
+    }
+}
+
+impl JsSide for Rc<Host> {
+    type Js = JsValue;
+
+    fn inspect(&self, js: &JsValue) -> JsShape<JsValue> {
+        if js.is_null() || js.is_undefined() {
+            JsShape::Null
+        } else if let Some(n) = js.as_f64() {
+            JsShape::Number(n)
+        } else if let Some(b) = js.as_bool() {
+            JsShape::Bool(b)
+        } else if let Some(s) = js.as_string() {
+            JsShape::String(s)
+        } else if js.is_function() && let Some(closure) = self.wrapped_closure(js) {
+            JsShape::Closure(closure)
+        } else if Array::is_array(js) {
+            JsShape::Array(js.unchecked_ref::<Array>().iter().collect())
+        } else if js.is_object() || js.is_function() {
+            // Getters and `Proxy` traps run while an object is read, so what
+            // they throw is reported rather than unwinding through the VM.
+            match plain_object_entries(js) {
+                Ok(Some(entries)) => JsShape::Object(entries),
+                Ok(None) => JsShape::HostObject,
+                Err(_) => JsShape::Threw,
+            }
+        } else {
+            JsShape::Unsupported
+        }
+    }
+    fn same(&self, a: &JsValue, b: &JsValue) -> bool {
+        return a == b;
+    }
+    fn type_name(&self, js: &JsValue) -> String {
+        return js_type_name(js);
+    }
+
+    fn null(&self) -> JsValue {
+        return JsValue::NULL;
+    }
+    fn number(&self, n: f64) -> JsValue {
+        return JsValue::from_f64(n);
+    }
+    fn bool(&self, b: bool) -> JsValue {
+        return JsValue::from_bool(b);
+    }
+    fn string(&self, s: &str) -> JsValue {
+        return JsValue::from_str(s);
+    }
+    fn array(&self, items: Vec<JsValue>) -> JsValue {
+        return items.into_iter().collect::<Array>().into();
+    }
+    fn object(&self, entries: Vec<(Rc<str>, JsValue)>) -> Option<JsValue> {
+        let pairs: Array = entries.iter()
+            .map(|(key, value)| Array::of2(&JsValue::from_str(key), value))
+            .collect();
+        // `fromEntries` defines own properties, so keys such as `__proto__`
+        // stay data rather than reaching the prototype.
+        return Object::from_entries(&pairs).ok().map(JsValue::from);
+    }
+    fn function(&self, closure: Value) -> JsValue {
+        return self.wrap_closure(closure);
+    }
+
+    fn retain(&self, object: JsValue) -> u64 {
+        return self.retain_handle(object);
+    }
+    fn handle(&self, id: u64) -> Option<JsValue> {
+        return self.handles.borrow().get(id).cloned();
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Object, js_name = entries, catch)]
+    fn object_entries(object: &JsValue) -> Result<Array, JsValue>;
+    #[wasm_bindgen(js_namespace = Object, js_name = getPrototypeOf, catch)]
+    fn prototype_of(object: &JsValue) -> Result<JsValue, JsValue>;
+}
+
+fn js_type_name(js: &JsValue) -> String {
+    if Array::is_array(js) {
+        return "array".to_owned();
+    }
+    if js.is_object() && matches!(is_plain_object(js), Ok(false)) {
+        // Class instances are named after their constructor, e.g. `HTMLCanvasElement`.
+        let constructor = prototype_of(js)
+            .and_then(|prototype| Reflect::get(&prototype, &JsValue::from_str("constructor")))
+            .ok()
+            .and_then(|constructor| constructor.dyn_into::<Function>().ok())
+            .map(|constructor| String::from(constructor.name()))
+            .filter(|name| !name.is_empty());
+        if let Some(name) = constructor {
+            return name;
+        }
+    }
+    return js.js_typeof().as_string().unwrap_or_else(|| "value".to_owned());
+}
+
+/// Whether `js` is a record literal (or `Object.create(null)`) rather than
+/// an instance of some class, which would lose its identity if flattened.
+/// Fails with whatever a `Proxy`'s `getPrototypeOf` trap throws.
+fn is_plain_object(js: &JsValue) -> Result<bool, JsValue> {
+    if !js.is_object() || Array::is_array(js) {
+        return Ok(false);
+    }
+    let prototype = prototype_of(js)?;
+    return Ok(prototype.is_null() || prototype_of(&prototype)?.is_null());
+}
+
+/// The own properties of `js` if it is a plain object, failing with whatever
+/// reading them throws.
+fn plain_object_entries(js: &JsValue) -> Result<Option<Vec<(String, JsValue)>>, JsValue> {
+    if !is_plain_object(js)? {
+        return Ok(None);
+    }
+    let entries = object_entries(js)?.iter()
+        .map(|pair| {
+            let pair: Array = pair.unchecked_into();
+            (pair.get(0).as_string().unwrap_or_default(), pair.get(1))
+        })
+        .collect();
+    return Ok(Some(entries));
+}
+
+/// Converts `value` for display, describing it as e.g. `<native function>`
+/// when it has no JS representation rather than failing.
+pub fn to_js_or_describe(value: &Value, cx: &mut Marshal<Rc<Host>>) -> JsValue {
+    return value.try_to_js(cx)
+        .unwrap_or_else(|_| JsValue::from_str(&format!("<{}>", type_name(value))));
+}
@@ -0,0 +1,241 @@
+//! State shared between a `WasmVm`, the natives compiled into it and the JS
+//! functions it hands out for script closures.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use gart::Value;
+use js_sys::{Array, Function, Map, Promise, Reflect};
+use wasm_bindgen::prelude::*;
+use crate::catalogue::{self, ErrorCode};
+use crate::convert::JsConvert;
+use crate::marshal::{ConversionError, Marshal, Position};
+use crate::vm_core::{Fault, Handles, HostLink, Outcome, PendingCall, State, Vm, DROPPED};
+
+pub struct Host {
+    pub(crate) link: HostLink,
+    pub(crate) max_depth: Cell<usize>,
+    /// JS objects scripts hold as opaque handles; dropped with the VM.
+    pub(crate) handles: RefCell<Handles<JsValue>>,
+    /// The handle id of each object in `handles`, so passing it again reuses the id.
+    handle_ids: Map,
+    /// Script closures passed to JS, with the function each is wrapped in.
+    closures: RefCell<Vec<(Value, JsValue)>>,
+    /// Indices into `closures`, by closure address and by wrapper.
+    closure_ids: RefCell<HashMap<*const (), usize>>,
+    wrapper_ids: Map,
+    /// The VM this host serves, once it has been compiled.
+    pub(crate) vm: RefCell<Weak<RefCell<Vm>>>,
+    /// The promise an asynchronous native most recently suspended the VM on.
+    pub(crate) awaiting: RefCell<Option<Promise>>,
+}
+
+impl Host {
+    pub fn new(max_depth: usize) -> Self {
+        return Self {
+            link: HostLink::default(),
+            max_depth: Cell::new(max_depth),
+            handles: RefCell::default(),
+            handle_ids: Map::new(),
+            closures: RefCell::default(),
+            closure_ids: RefCell::default(),
+            wrapper_ids: Map::new(),
+            vm: RefCell::new(Weak::new()),
+            awaiting: RefCell::new(None),
+        };
+    }
+    pub fn marshal(self: &Rc<Self>) -> Marshal<'_, Rc<Self>> {
+        return Marshal::new(self.max_depth.get(), self);
+    }
+
+    /// The handle id for `object`, reusing its id if scripts already hold it.
+    pub(crate) fn retain_handle(&self, object: JsValue) -> u64 {
+        if let Some(id) = self.handle_ids.get(&object).as_f64() {
+            return id as u64;
+        }
+        let id = self.handles.borrow_mut().insert(object.clone());
+        self.handle_ids.set(&object, &JsValue::from_f64(id as f64));
+        return id;
+    }
+    /// Drops the handle for `object`, returning whether there was one.
+    pub(crate) fn release_handle(&self, object: &JsValue) -> bool {
+        let Some(id) = self.handle_ids.get(object).as_f64() else {
+            return false;
+        };
+        self.handle_ids.delete(object);
+        return self.handles.borrow_mut().release(id as u64).is_some();
+    }
+
+    /// The JS function for `closure`, reusing the one it was wrapped in before.
+    pub(crate) fn wrap_closure(self: &Rc<Self>, closure: Value) -> JsValue {
+        let Value::Closure(function) = &closure else {
+            return callable(self, closure);
+        };
+        let address = Rc::as_ptr(function) as *const ();
+        if let Some(&index) = self.closure_ids.borrow().get(&address) {
+            return self.closures.borrow()[index].1.clone();
+        }
+        let wrapper = callable(self, closure.clone());
+        let mut closures = self.closures.borrow_mut();
+        self.closure_ids.borrow_mut().insert(address, closures.len());
+        self.wrapper_ids.set(&wrapper, &JsValue::from_f64(closures.len() as f64));
+        closures.push((closure, wrapper.clone()));
+        return wrapper;
+    }
+    /// The closure `wrapper` was made for by `wrap_closure`, if any.
+    pub(crate) fn wrapped_closure(&self, wrapper: &JsValue) -> Option<Value> {
+        let index = self.wrapper_ids.get(wrapper).as_f64()?;
+        return Some(self.closures.borrow()[index as usize].0.clone());
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        // Callers awaiting queued calls would otherwise never settle.
+        for call in self.link.calls.drain() {
+            (call.done)(Err(Fault::new(DROPPED)));
+        }
+    }
+}
+
+#[wasm_bindgen(inline_js = "export function variadic(call) { return (...args) => call(args); }")]
+extern "C" {
+    /// Adapts a one-argument closure into a function taking any number of arguments.
+    fn variadic(call: &JsValue) -> Function;
+}
+
+/// Wraps a script closure as a JS function that runs it in the VM behind `host`.
+///
+/// Called while the VM is idle, the function runs the closure and returns its
+/// result, or a `Promise` of it if the closure stops part-way (e.g. awaiting an
+/// asynchronous native). Called while the script is still being stepped (or
+/// from inside a native), the call is queued and a `Promise` returned.
+pub fn callable(host: &Rc<Host>, callee: Value) -> JsValue {
+    let host = Rc::downgrade(host);
+    let call = Closure::<dyn Fn(Array) -> Result<JsValue, JsValue>>::new(move |args: Array| {
+        call_from_js(&host, &callee, args)
+    });
+    return variadic(&call.into_js_value()).into();
+}
+
+fn call_from_js(host: &Weak<Host>, callee: &Value, args: Array) -> Result<JsValue, JsValue> {
+    let host = host.upgrade().ok_or_else(|| fault_error(&Fault::new(DROPPED)))?;
+    let vm = host.vm.borrow().upgrade().ok_or_else(|| fault_error(&Fault::new(DROPPED)))?;
+
+    let mut cx = host.marshal();
+    let mut gart_args = Vec::with_capacity(args.length() as usize);
+    for (index, arg) in args.iter().enumerate() {
+        let arg = Value::try_from_js(arg, &mut cx)
+            .map_err(|err| conversion_js_error(&err.at(Position::Argument(index))))?;
+        gart_args.push(arg);
+    }
+
+    if let Ok(mut vm) = vm.try_borrow_mut() && vm.is_idle() {
+        let called = call_now(&host, &mut vm, callee.clone(), gart_args).map_err(|fault| fault_error(&fault))?;
+        return match called {
+            Called::Returned(returned) => returned.map_err(|fault| fault_error(&fault))?
+                .try_to_js(&mut cx)
+                .map_err(|err| conversion_js_error(&err.at(Position::ReturnValue))),
+            Called::Cancelled(fault) => Err(fault_error(&fault)),
+            Called::Stopped(_, promise) => Ok(promise.into()),
+        };
+    }
+
+    let weak = Rc::downgrade(&host);
+    let mut gart_args = Some(gart_args);
+    let promise = Promise::new(&mut |resolve, reject| {
+        let weak = weak.clone();
+        host.link.calls.push(PendingCall {
+            callee: callee.clone(),
+            args: gart_args.take().unwrap_or_default(),
+            done: Box::new(move |returned| settle(&weak, returned, &resolve, &reject)),
+        });
+    });
+    return Ok(promise.into());
+}
+
+/// How a call into the script went by the time control came back.
+pub(crate) enum Called {
+    Returned(Result<Value, Fault>),
+    /// An interrupt stopped the VM before the call began, so it never ran.
+    Cancelled(Fault),
+    /// The call stopped part-way, as the outcome says; the promise settles
+    /// with its result once the VM is resumed and it returns.
+    Stopped(Outcome, Promise),
+}
+
+/// Where a started call's result goes: kept for the caller until it is handed
+/// a promise instead, which the result then settles.
+enum Reply {
+    Waiting,
+    Returned(Result<Value, Fault>),
+    Promised(Function, Function),
+}
+
+/// Runs `callee` in the idle `vm`, failing if the VM refuses the call.
+pub(crate) fn call_now(host: &Rc<Host>, vm: &mut Vm, callee: Value, args: Vec<Value>) -> Result<Called, Fault> {
+    let reply = Rc::new(RefCell::new(Reply::Waiting));
+    let done = {
+        let reply = reply.clone();
+        let host = Rc::downgrade(host);
+        move |returned| {
+            let waiting = std::mem::replace(&mut *reply.borrow_mut(), Reply::Waiting);
+            match waiting {
+                Reply::Promised(resolve, reject) => settle(&host, returned, &resolve, &reject),
+                _ => *reply.borrow_mut() = Reply::Returned(returned),
+            }
+        }
+    };
+
+    let outcome = vm.start_call(callee, args, Box::new(done))?;
+    let replied = std::mem::replace(&mut *reply.borrow_mut(), Reply::Waiting);
+    return Ok(match replied {
+        Reply::Returned(Err(fault)) if outcome.state == State::Cancelled => Called::Cancelled(fault),
+        Reply::Returned(returned) => Called::Returned(returned),
+        _ => {
+            let promise = Promise::new(&mut |resolve, reject| *reply.borrow_mut() = Reply::Promised(resolve, reject));
+            Called::Stopped(outcome, promise)
+        },
+    });
+}
+
+fn settle(host: &Weak<Host>, returned: Result<Value, Fault>, resolve: &Function, reject: &Function) {
+    let settled = match (returned, host.upgrade()) {
+        (Ok(value), Some(host)) => value.try_to_js(&mut host.marshal())
+            .map_err(|err| conversion_js_error(&err.at(Position::ReturnValue))),
+        (Ok(_), None) => Err(fault_error(&Fault::new(DROPPED))),
+        (Err(fault), _) => Err(fault_error(&fault)),
+    };
+    settle_promise(settled, resolve, reject);
+}
+
+/// Resolves or rejects the promise `resolve` and `reject` belong to.
+pub(crate) fn settle_promise(settled: Result<JsValue, JsValue>, resolve: &Function, reject: &Function) {
+    // Settling a promise only queues reactions, so neither call can throw here.
+    let _ = match settled {
+        Ok(value) => resolve.call1(&JsValue::NULL, &value),
+        Err(error) => reject.call1(&JsValue::NULL, &error),
+    };
+}
+
+pub(crate) fn js_error(message: &str) -> JsValue {
+    return js_sys::Error::new(message).into();
+}
+
+/// An `Error` for a runtime error, with the `code` and `category` a `RuntimeErr` would have.
+pub(crate) fn fault_error(fault: &Fault) -> JsValue {
+    return coded_error(&fault.message, catalogue::for_fault(fault));
+}
+
+/// An `Error` for a failed conversion, coded like a `RuntimeErr` for one.
+pub(crate) fn conversion_js_error(err: &ConversionError) -> JsValue {
+    return coded_error(&err.to_string(), catalogue::for_conversion());
+}
+
+fn coded_error(message: &str, error_code: &ErrorCode) -> JsValue {
+    let error = js_error(message);
+    // Defining properties on a fresh `Error` cannot fail.
+    let _ = Reflect::set(&error, &JsValue::from_str("code"), &JsValue::from_str(error_code.code));
+    let _ = Reflect::set(&error, &JsValue::from_str("category"), &JsValue::from_str(error_code.category.as_str()));
+    return error;
+}
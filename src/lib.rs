@@ -1 +1,8 @@
-pub mod wasm_vm;
\ No newline at end of file
+pub mod catalogue;
+pub mod convert;
+pub mod host;
+pub mod marshal;
+pub mod vm_core;
+pub mod wasm_vm;
+
+pub use wasm_vm::{compile, error_catalogue, CallResult, CompileResult, CompilerErr, ErrorInfo, EvalResult, InterruptHandle, JsNativeFn, NativeErr, Output, RuntimeErr, SourceLocation, StackFrame, Status, WasmVm};
@@ -0,0 +1,484 @@
+//! Host-agnostic conversion between gart values and the host's JS values.
+//!
+//! The walk over lists, maps, arrays and objects lives here, behind `JsSide`,
+//! so depth limits and cycle detection can be tested without a JS engine.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use gart::Value;
+use crate::vm_core::type_name;
+
+/// How deep lists and maps may nest before conversion gives up, unless the VM is told otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// The JS operations conversion is built from.
+pub trait JsSide {
+    type Js: Clone;
+
+    /// What `js` is, with the contents of arrays and plain objects. Objects list
+    /// integer-like keys ("0", "7") first, in ascending order, then the rest in
+    /// insertion order, so maps keyed that way come back reordered.
+    fn inspect(&self, js: &Self::Js) -> JsShape<Self::Js>;
+    /// Whether `a` and `b` are the same object.
+    fn same(&self, a: &Self::Js, b: &Self::Js) -> bool;
+    fn type_name(&self, js: &Self::Js) -> String;
+
+    fn null(&self) -> Self::Js;
+    fn number(&self, n: f64) -> Self::Js;
+    fn bool(&self, b: bool) -> Self::Js;
+    fn string(&self, s: &str) -> Self::Js;
+    fn array(&self, items: Vec<Self::Js>) -> Self::Js;
+    /// A plain object with `entries` as its own properties, in the order `inspect`
+    /// would list them, or `None` if it cannot be built.
+    fn object(&self, entries: Vec<(Rc<str>, Self::Js)>) -> Option<Self::Js>;
+    /// A JS function that calls the script closure `closure`; the same one each
+    /// time for the same closure, so the host can compare them.
+    fn function(&self, closure: Value) -> Self::Js;
+
+    /// Keeps `object` alive for the script, returning its handle id.
+    fn retain(&self, object: Self::Js) -> u64;
+    fn handle(&self, id: u64) -> Option<Self::Js>;
+}
+
+/// How a JS value converts.
+pub enum JsShape<J> {
+    Null,
+    Number(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<J>),
+    /// A plain object's own properties, in enumeration order.
+    Object(Vec<(String, J)>),
+    /// A function `JsSide::function` made, which converts back to its closure.
+    Closure(Value),
+    /// A class instance or function, which scripts can only hold as a handle.
+    HostObject,
+    Unsupported,
+    /// Reading the value threw, e.g. in a getter or a `Proxy` trap.
+    Threw,
+}
+
+/// State for one conversion, tracking the containers currently being walked.
+pub struct Marshal<'a, S: JsSide> {
+    max_depth: usize,
+    path: Vec<Container<S::Js>>,
+    side: &'a S,
+}
+
+/// Identity of a container on the conversion path.
+enum Container<J> {
+    Gart(*const ()),
+    Js(J),
+}
+
+impl<'a, S: JsSide> Marshal<'a, S> {
+    pub fn new(max_depth: usize, side: &'a S) -> Self {
+        return Self {
+            max_depth,
+            path: vec![],
+            side,
+        };
+    }
+
+    /// Lists convert to arrays and maps to plain objects.
+    pub fn value_to_js(&mut self, value: &Value) -> Result<S::Js, ConversionError> {
+        let side = self.side;
+        return match value {
+            Value::Number(n) => Ok(side.number(*n)),
+            Value::Bool(b) => Ok(side.bool(*b)),
+            Value::String(s) => Ok(side.string(s)),
+            Value::Null => Ok(side.null()),
+            Value::List(items) => {
+                let identity = Container::Gart(Rc::as_ptr(items) as *const ());
+                self.within(identity, |reason| ConversionError::value(value, reason), |cx| {
+                    let mut array = Vec::with_capacity(items.borrow().len());
+                    for item in items.borrow().iter() {
+                        array.push(cx.value_to_js(item)?);
+                    }
+                    Ok(side.array(array))
+                })
+            },
+            Value::Map(entries) => {
+                let identity = Container::Gart(Rc::as_ptr(entries) as *const ());
+                self.within(identity, |reason| ConversionError::value(value, reason), |cx| {
+                    let mut pairs = Vec::with_capacity(entries.borrow().len());
+                    for (key, item) in entries.borrow().iter() {
+                        pairs.push((key.clone(), cx.value_to_js(item)?));
+                    }
+                    side.object(pairs).ok_or_else(|| ConversionError::value(value, Reason::Unsupported))
+                })
+            },
+            Value::Closure(_) => Ok(side.function(value.clone())),
+            Value::Opaque(id) => side.handle(*id)
+                .ok_or_else(|| ConversionError::value(value, Reason::UnknownHandle)),
+            _ => Err(ConversionError::value(value, Reason::Unsupported)),
+        };
+    }
+
+    /// Arrays convert to lists and plain objects to maps; other objects
+    /// become opaque handles, which convert back to the same object.
+    pub fn value_from_js(&mut self, js: S::Js) -> Result<Value, ConversionError> {
+        let side = self.side;
+        return match side.inspect(&js) {
+            JsShape::Null => Ok(Value::Null),
+            JsShape::Number(n) => Ok(Value::Number(n)),
+            JsShape::Bool(b) => Ok(Value::Bool(b)),
+            JsShape::String(s) => Ok(Value::String(s.into())),
+            JsShape::Array(items) => {
+                self.within(Container::Js(js.clone()), |reason| ConversionError::js(side.type_name(&js), reason), |cx| {
+                    let mut list = Vec::with_capacity(items.len());
+                    for item in items {
+                        list.push(cx.value_from_js(item)?);
+                    }
+                    Ok(Value::List(Rc::new(RefCell::new(list))))
+                })
+            },
+            JsShape::Object(pairs) => {
+                self.within(Container::Js(js.clone()), |reason| ConversionError::js(side.type_name(&js), reason), |cx| {
+                    let mut entries = Vec::with_capacity(pairs.len());
+                    for (key, value) in pairs {
+                        entries.push((key.into(), cx.value_from_js(value)?));
+                    }
+                    Ok(Value::Map(Rc::new(RefCell::new(entries))))
+                })
+            },
+            JsShape::Closure(closure) => Ok(closure),
+            JsShape::HostObject => Ok(Value::Opaque(side.retain(js))),
+            JsShape::Unsupported => Err(ConversionError::js(side.type_name(&js), Reason::Unsupported)),
+            JsShape::Threw => Err(ConversionError::js(side.type_name(&js), Reason::Threw)),
+        };
+    }
+
+    /// Runs `convert` one level deeper, failing instead if that would exceed
+    /// the depth limit or re-enter a container that is already being converted.
+    fn within<T>(
+        &mut self,
+        container: Container<S::Js>,
+        error: impl Fn(Reason) -> ConversionError,
+        convert: impl FnOnce(&mut Self) -> Result<T, ConversionError>,
+    ) -> Result<T, ConversionError> {
+        if self.path.len() >= self.max_depth {
+            return Err(error(Reason::TooDeep(self.max_depth)));
+        }
+        if self.path.iter().any(|entered| self.is_same(entered, &container)) {
+            return Err(error(Reason::Cycle));
+        }
+
+        self.path.push(container);
+        let result = convert(self);
+        self.path.pop();
+        return result;
+    }
+
+    fn is_same(&self, a: &Container<S::Js>, b: &Container<S::Js>) -> bool {
+        return match (a, b) {
+            (Container::Gart(a), Container::Gart(b)) => a == b,
+            (Container::Js(a), Container::Js(b)) => self.side.same(a, b),
+            _ => false,
+        };
+    }
+}
+
+/// Which way a failed conversion was going.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ToJs,
+    FromJs,
+}
+
+/// Where in a native call the offending value sat.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Position {
+    /// Zero-based argument index.
+    Argument(usize),
+    ReturnValue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    Unsupported,
+    /// Nesting went past the configured limit.
+    TooDeep(usize),
+    /// A container contains itself.
+    Cycle,
+    /// A handle that does not belong to this VM.
+    UnknownHandle,
+    /// Reading the value threw.
+    Threw,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConversionError {
+    pub direction: Direction,
+    pub reason: Reason,
+    pub type_name: String,
+    pub position: Option<Position>,
+}
+
+impl ConversionError {
+    fn value(value: &Value, reason: Reason) -> Self {
+        return Self {
+            direction: Direction::ToJs,
+            reason,
+            type_name: type_name(value).to_owned(),
+            position: None,
+        };
+    }
+    fn js(type_name: String, reason: Reason) -> Self {
+        return Self {
+            direction: Direction::FromJs,
+            reason,
+            type_name,
+            position: None,
+        };
+    }
+    pub fn at(self, position: Position) -> Self {
+        return Self { position: Some(position), ..self };
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = match self.direction {
+            Direction::ToJs => "gart",
+            Direction::FromJs => "JS",
+        };
+        match (self.reason, self.direction) {
+            (Reason::Unsupported, Direction::ToJs) => write!(f, "cannot pass a gart {} to JS", self.type_name)?,
+            (Reason::Unsupported, Direction::FromJs) => write!(f, "cannot use a JS {} as a gart value", self.type_name)?,
+            (Reason::TooDeep(max_depth), _) => write!(f, "{} {} nested deeper than {} levels", side, self.type_name, max_depth)?,
+            (Reason::Cycle, _) => write!(f, "{} {} contains itself", side, self.type_name)?,
+            (Reason::UnknownHandle, _) => write!(f, "{} {} does not belong to this VM", side, self.type_name)?,
+            (Reason::Threw, _) => write!(f, "{} {} threw while being read", side, self.type_name)?,
+        }
+        return match self.position {
+            Some(Position::Argument(index)) => write!(f, " (argument {})", index + 1),
+            Some(Position::ReturnValue) => write!(f, " (return value)"),
+            None => Ok(()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gart::interpreter::Interpreter;
+    use crate::vm_core::time_native;
+
+    /// A stand-in for JS values: containers and instances are shared, so they have identity.
+    #[derive(Clone, Debug)]
+    enum Fake {
+        Null,
+        Number(f64),
+        Bool(bool),
+        String(String),
+        Array(Rc<RefCell<Vec<Fake>>>),
+        Object(Rc<RefCell<Vec<(String, Fake)>>>),
+        /// An instance of the named class.
+        Instance(Rc<str>),
+        /// The wrapper a script closure converts to, by index into `FakeJs::closures`.
+        Function(usize),
+        Symbol,
+        /// An object with a getter that throws.
+        Trap,
+    }
+
+    #[derive(Default)]
+    struct FakeJs {
+        handles: RefCell<Vec<Fake>>,
+        closures: RefCell<Vec<Value>>,
+    }
+
+    impl JsSide for FakeJs {
+        type Js = Fake;
+
+        fn inspect(&self, js: &Fake) -> JsShape<Fake> {
+            return match js {
+                Fake::Null => JsShape::Null,
+                Fake::Number(n) => JsShape::Number(*n),
+                Fake::Bool(b) => JsShape::Bool(*b),
+                Fake::String(s) => JsShape::String(s.clone()),
+                Fake::Array(items) => JsShape::Array(items.borrow().clone()),
+                Fake::Object(entries) => JsShape::Object(entries.borrow().clone()),
+                Fake::Function(index) => JsShape::Closure(self.closures.borrow()[*index].clone()),
+                Fake::Instance(_) => JsShape::HostObject,
+                Fake::Symbol => JsShape::Unsupported,
+                Fake::Trap => JsShape::Threw,
+            };
+        }
+        fn same(&self, a: &Fake, b: &Fake) -> bool {
+            return match (a, b) {
+                (Fake::Array(a), Fake::Array(b)) => Rc::ptr_eq(a, b),
+                (Fake::Object(a), Fake::Object(b)) => Rc::ptr_eq(a, b),
+                (Fake::Instance(a), Fake::Instance(b)) => Rc::ptr_eq(a, b),
+                _ => false,
+            };
+        }
+        fn type_name(&self, js: &Fake) -> String {
+            return match js {
+                Fake::Array(_) => "array".to_owned(),
+                Fake::Instance(class) => class.to_string(),
+                Fake::Symbol => "symbol".to_owned(),
+                Fake::Trap => "object".to_owned(),
+                _ => "value".to_owned(),
+            };
+        }
+        fn null(&self) -> Fake {
+            return Fake::Null;
+        }
+        fn number(&self, n: f64) -> Fake {
+            return Fake::Number(n);
+        }
+        fn bool(&self, b: bool) -> Fake {
+            return Fake::Bool(b);
+        }
+        fn string(&self, s: &str) -> Fake {
+            return Fake::String(s.to_owned());
+        }
+        fn array(&self, items: Vec<Fake>) -> Fake {
+            return Fake::Array(Rc::new(RefCell::new(items)));
+        }
+        fn object(&self, entries: Vec<(Rc<str>, Fake)>) -> Option<Fake> {
+            let entries = entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+            return Some(Fake::Object(Rc::new(RefCell::new(entries))));
+        }
+        fn function(&self, closure: Value) -> Fake {
+            let mut closures = self.closures.borrow_mut();
+            closures.push(closure);
+            return Fake::Function(closures.len() - 1);
+        }
+        fn retain(&self, object: Fake) -> u64 {
+            let mut handles = self.handles.borrow_mut();
+            if let Some(id) = handles.iter().position(|held| self.same(held, &object)) {
+                return id as u64;
+            }
+            handles.push(object);
+            return (handles.len() - 1) as u64;
+        }
+        fn handle(&self, id: u64) -> Option<Fake> {
+            return self.handles.borrow().get(id as usize).cloned();
+        }
+    }
+
+    fn list(items: Vec<Value>) -> Value {
+        return Value::List(Rc::new(RefCell::new(items)));
+    }
+
+    fn map(entries: Vec<(&str, Value)>) -> Value {
+        return Value::Map(Rc::new(RefCell::new(entries.into_iter().map(|(key, value)| (key.into(), value)).collect())));
+    }
+
+    fn array(items: Vec<Fake>) -> Fake {
+        return Fake::Array(Rc::new(RefCell::new(items)));
+    }
+
+    fn show(value: &Value) -> String {
+        return match value {
+            Value::List(items) => format!("[{}]", items.borrow().iter().map(show).collect::<Vec<_>>().join(", ")),
+            Value::Map(entries) => format!("{{{}}}", entries.borrow().iter()
+                .map(|(key, value)| format!("{}: {}", key, show(value)))
+                .collect::<Vec<_>>()
+                .join(", ")),
+            Value::String(s) => format!("{:?}", s),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Null => "null".to_owned(),
+            Value::Opaque(id) => format!("#{}", id),
+            _ => type_name(value).to_owned(),
+        };
+    }
+
+    #[test]
+    fn lists_and_maps_round_trip() {
+        let js = FakeJs::default();
+        let value = list(vec![
+            Value::Number(1.0),
+            Value::String("two".into()),
+            Value::Null,
+            map(vec![("b", Value::Bool(true)), ("a", list(vec![Value::Number(3.0)]))]),
+        ]);
+
+        let converted = Marshal::new(DEFAULT_MAX_DEPTH, &js).value_to_js(&value).unwrap();
+        let Fake::Array(items) = &converted else { panic!("lists convert to arrays") };
+        assert!(matches!(&items.borrow()[3], Fake::Object(entries) if entries.borrow()[0].0 == "b"));
+
+        let back = Marshal::new(DEFAULT_MAX_DEPTH, &js).value_from_js(converted).unwrap();
+        assert_eq!(show(&back), r#"[1, "two", null, {b: true, a: [3]}]"#);
+    }
+
+    #[test]
+    fn nesting_is_limited_to_max_depth() {
+        let js = FakeJs::default();
+        let value = list(vec![list(vec![list(vec![])])]);
+        let nested = array(vec![array(vec![array(vec![])])]);
+
+        assert!(Marshal::new(3, &js).value_to_js(&value).is_ok());
+        assert_eq!(Marshal::new(2, &js).value_to_js(&value).err().unwrap().reason, Reason::TooDeep(2));
+        assert!(Marshal::new(3, &js).value_from_js(nested.clone()).is_ok());
+        assert_eq!(Marshal::new(2, &js).value_from_js(nested).err().unwrap().reason, Reason::TooDeep(2));
+    }
+
+    #[test]
+    fn containers_that_contain_themselves_are_cycles() {
+        let js = FakeJs::default();
+
+        let items = Rc::new(RefCell::new(vec![]));
+        items.borrow_mut().push(Value::List(items.clone()));
+        let err = Marshal::new(8, &js).value_to_js(&Value::List(items.clone())).err().unwrap();
+        assert_eq!((err.reason, err.direction), (Reason::Cycle, Direction::ToJs));
+        items.borrow_mut().clear();
+
+        let object = Rc::new(RefCell::new(vec![]));
+        object.borrow_mut().push(("self".to_owned(), array(vec![Fake::Object(object.clone())])));
+        let err = Marshal::new(8, &js).value_from_js(Fake::Object(object.clone())).err().unwrap();
+        assert_eq!((err.reason, err.direction), (Reason::Cycle, Direction::FromJs));
+        object.borrow_mut().clear();
+
+        // Sharing a container between siblings is not a cycle.
+        let shared = array(vec![]);
+        assert!(Marshal::new(8, &js).value_from_js(array(vec![shared.clone(), shared])).is_ok());
+    }
+
+    #[test]
+    fn objects_that_throw_while_read_fail_to_convert() {
+        let js = FakeJs::default();
+        let err = Marshal::new(8, &js).value_from_js(array(vec![Fake::Trap])).err().unwrap();
+        assert_eq!(err.reason, Reason::Threw);
+        assert_eq!(err.at(Position::ReturnValue).to_string(), "JS object threw while being read (return value)");
+    }
+
+    #[test]
+    fn host_objects_are_held_by_handle() {
+        let js = FakeJs::default();
+        let canvas = Fake::Instance("HTMLCanvasElement".into());
+
+        let handle = Marshal::new(8, &js).value_from_js(canvas.clone()).unwrap();
+        let Value::Opaque(id) = handle else { panic!("instances convert to handles") };
+        assert!(matches!(Marshal::new(8, &js).value_from_js(canvas.clone()).unwrap(), Value::Opaque(again) if again == id));
+
+        let back = Marshal::new(8, &js).value_to_js(&Value::Opaque(id)).unwrap();
+        assert!(js.same(&back, &canvas));
+        assert_eq!(Marshal::new(8, &js).value_to_js(&Value::Opaque(id + 1)).err().unwrap().reason, Reason::UnknownHandle);
+    }
+
+    #[test]
+    fn closures_round_trip_through_functions_and_natives_do_not_convert() {
+        let js = FakeJs::default();
+        let mut interpreter = Interpreter::new("fun answer() { return 42; }".to_owned(), vec![]).ok().unwrap();
+        interpreter.run().ok().unwrap();
+        let answer = interpreter.get_global("answer").unwrap();
+
+        let function = Marshal::new(8, &js).value_to_js(&answer).unwrap();
+        assert!(matches!(function, Fake::Function(_)));
+        let back = Marshal::new(8, &js).value_from_js(function).unwrap();
+        assert!(matches!((&back, &answer), (Value::Closure(a), Value::Closure(b)) if Rc::ptr_eq(a, b)));
+
+        let native = Value::NativeFunction(Rc::new(time_native(|| 0.0)));
+        let err = Marshal::new(8, &js).value_to_js(&native).err().unwrap();
+        assert_eq!((err.reason, err.to_string()), (Reason::Unsupported, "cannot pass a gart native function to JS".to_owned()));
+
+        let err = Marshal::new(8, &js).value_from_js(Fake::Symbol).err().unwrap();
+        assert_eq!(err.at(Position::Argument(0)).to_string(), "cannot use a JS symbol as a gart value (argument 1)");
+    }
+}
@@ -0,0 +1,1711 @@
+//! Host-agnostic half of the VM bindings.
+//!
+//! Nothing in here touches `wasm_bindgen` or `js_sys`, so it builds and is
+//! tested on a plain native target. `wasm_vm` wraps these types for JS.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::Rc;
+use gart::interpreter::{CompilerError, FrameInfo, Interpreter, RuntimeError, Span};
+use gart::{NativeFunction, Value};
+
+/// A compile error with its source span.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub start: usize,
+    pub len: usize,
+    pub message: String,
+}
+
+impl From<CompilerError> for Diagnostic {
+    fn from(err: CompilerError) -> Self {
+        return Self {
+            line: err.line,
+            start: err.start,
+            len: err.len,
+            message: err.message,
+        };
+    }
+}
+
+/// A span of the script's source, shaped like a `Diagnostic`'s.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl From<Span> for Location {
+    fn from(span: Span) -> Self {
+        return Self { line: span.line, column: span.start, len: span.len };
+    }
+}
+
+/// One active call in a paused or failed program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// The function's name, or gart's name for the top-level script.
+    pub name: String,
+    pub location: Option<Location>,
+    /// Index of the next instruction in the function's bytecode.
+    pub offset: usize,
+}
+
+impl From<FrameInfo> for Frame {
+    fn from(frame: FrameInfo) -> Self {
+        return Self {
+            name: frame.name,
+            location: frame.span.map(Location::from),
+            offset: frame.ip,
+        };
+    }
+}
+
+/// An error raised while the script was running.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub message: String,
+    /// Set when the error came from a host native rather than the script itself.
+    pub native: Option<NativeError>,
+    /// The calls active when the error was raised, innermost first.
+    pub stack: Vec<Frame>,
+}
+
+impl Fault {
+    pub fn new(message: impl Into<String>) -> Self {
+        return Self { message: message.into(), native: None, stack: vec![] };
+    }
+
+    /// Where in the source the error was raised, when it came from the script.
+    pub fn location(&self) -> Option<Location> {
+        return self.stack.first().and_then(|frame| frame.location);
+    }
+}
+
+impl From<RuntimeError> for Fault {
+    fn from(err: RuntimeError) -> Self {
+        let stack = err.trace.into_iter().map(Frame::from).collect();
+        return Self { message: err.message, native: None, stack };
+    }
+}
+
+impl From<NativeError> for Fault {
+    fn from(err: NativeError) -> Self {
+        let message = match &err.name {
+            Some(name) => format!("Native '{}' threw {}: {}", err.native, name, err.message),
+            None => format!("Native '{}' threw: {}", err.native, err.message),
+        };
+        return Self { message, native: Some(err), stack: vec![] };
+    }
+}
+
+/// Why an expression evaluated against the paused program produced no value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    Compile(Vec<Diagnostic>),
+    Runtime(Fault),
+}
+
+impl From<gart::interpreter::EvalError> for EvalError {
+    fn from(err: gart::interpreter::EvalError) -> Self {
+        return match err {
+            gart::interpreter::EvalError::Compile(errors) => Self::Compile(errors.into_iter().map(Diagnostic::from).collect()),
+            gart::interpreter::EvalError::Runtime(err) => Self::Runtime(Fault::from(err)),
+        };
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Self::Compile(diagnostics) => match diagnostics.first() {
+                Some(diagnostic) => write!(f, "{}", diagnostic.message),
+                None => write!(f, "Invalid expression."),
+            },
+            Self::Runtime(fault) => write!(f, "{}", fault.message),
+        };
+    }
+}
+
+/// What a host native threw, as far as the host could describe it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NativeError {
+    pub native: String,
+    pub name: Option<String>,
+    pub message: String,
+    pub stack: Option<String>,
+}
+
+/// Lets natives report failures that gart's `Value`-returning natives cannot express.
+///
+/// Shared between a `Vm` and the natives it was compiled with; the VM checks it after every step.
+#[derive(Clone, Default)]
+pub struct NativeErrors(Rc<RefCell<Option<NativeError>>>);
+
+impl NativeErrors {
+    /// Records `err` unless an earlier failure from the same step is still pending.
+    pub fn raise(&self, err: NativeError) {
+        self.0.borrow_mut().get_or_insert(err);
+    }
+    pub fn take(&self) -> Option<NativeError> {
+        return self.0.borrow_mut().take();
+    }
+}
+
+/// A call into the script requested by the host, run once the VM is otherwise idle.
+pub struct PendingCall {
+    pub callee: Value,
+    pub args: Vec<Value>,
+    /// Receives the call's return value, or the error that stopped it.
+    pub done: Box<dyn FnOnce(Result<Value, Fault>)>,
+}
+
+/// Calls queued by the host, reachable without borrowing the `Vm` so callbacks
+/// invoked from inside a native can still line up work.
+#[derive(Clone, Default)]
+pub struct CallQueue(Rc<RefCell<VecDeque<PendingCall>>>);
+
+impl CallQueue {
+    pub fn push(&self, call: PendingCall) {
+        self.0.borrow_mut().push_back(call);
+    }
+    fn pop(&self) -> Option<PendingCall> {
+        return self.0.borrow_mut().pop_front();
+    }
+    /// Removes every queued call, e.g. to fail them when the VM goes away.
+    pub fn drain(&self) -> Vec<PendingCall> {
+        return self.0.borrow_mut().drain(..).collect();
+    }
+    fn is_empty(&self) -> bool {
+        return self.0.borrow().is_empty();
+    }
+}
+
+/// Where a native that handed back a promise is up to.
+enum Await {
+    Idle,
+    Pending(u64),
+    Settled(Result<Value, Fault>),
+}
+
+/// Lets an asynchronous native suspend the VM until its result arrives.
+///
+/// The native calls `suspend` and returns a placeholder; the VM stops before
+/// the next instruction and, once the host calls `settle`, swaps the real
+/// result in for the placeholder and carries on.
+#[derive(Clone)]
+pub struct Suspension(Rc<RefCell<Awaiting>>);
+
+struct Awaiting {
+    state: Await,
+    /// The last ticket handed out.
+    ticket: u64,
+    /// Set while code that cannot wait runs, e.g. a watch expression.
+    blocked: bool,
+    /// Whether anything tried to suspend while blocked.
+    refused: bool,
+}
+
+impl Default for Suspension {
+    fn default() -> Self {
+        return Self(Rc::new(RefCell::new(Awaiting { state: Await::Idle, ticket: 0, blocked: false, refused: false })));
+    }
+}
+
+impl Suspension {
+    /// Marks the VM as waiting, returning the ticket to settle it with. Returns
+    /// `None` when waiting is not allowed right now, leaving any promise the VM
+    /// already awaits in place; the native's placeholder then stands.
+    pub fn suspend(&self) -> Option<u64> {
+        let mut awaiting = self.0.borrow_mut();
+        if awaiting.blocked {
+            awaiting.refused = true;
+            return None;
+        }
+        awaiting.ticket += 1;
+        awaiting.state = Await::Pending(awaiting.ticket);
+        return Some(awaiting.ticket);
+    }
+    /// Delivers the awaited result. Ignored if the VM stopped waiting on `ticket`
+    /// in the meantime, e.g. because the program ended or the VM was reset.
+    pub fn settle(&self, ticket: u64, result: Result<Value, Fault>) {
+        let mut awaiting = self.0.borrow_mut();
+        if let Await::Pending(pending) = awaiting.state && pending == ticket {
+            awaiting.state = Await::Settled(result);
+        }
+    }
+    pub fn is_pending(&self) -> bool {
+        return matches!(self.0.borrow().state, Await::Pending(_));
+    }
+    fn take_settled(&self) -> Option<Result<Value, Fault>> {
+        let mut awaiting = self.0.borrow_mut();
+        return match std::mem::replace(&mut awaiting.state, Await::Idle) {
+            Await::Settled(result) => Some(result),
+            other => {
+                awaiting.state = other;
+                None
+            },
+        };
+    }
+    fn abandon(&self) {
+        self.0.borrow_mut().state = Await::Idle;
+    }
+    /// Makes `suspend` refuse until `unblock`.
+    fn block(&self) {
+        let mut awaiting = self.0.borrow_mut();
+        awaiting.blocked = true;
+        awaiting.refused = false;
+    }
+    /// Lets natives suspend again, returning whether any tried while blocked.
+    fn unblock(&self) -> bool {
+        let mut awaiting = self.0.borrow_mut();
+        awaiting.blocked = false;
+        return std::mem::take(&mut awaiting.refused);
+    }
+}
+
+/// Everything natives and host callbacks use to reach the VM they belong to.
+#[derive(Clone, Default)]
+pub struct HostLink {
+    pub native_errors: NativeErrors,
+    pub calls: CallQueue,
+    pub suspension: Suspension,
+    pub interrupt: Interrupt,
+}
+
+/// Lets the host stop a run at the next safe point, e.g. from a Stop button.
+///
+/// Runs check it every `CHECK_INTERVAL` instructions and stop with `Cancelled`,
+/// leaving the VM where it was so it can be resumed or reset.
+#[derive(Clone, Default)]
+pub struct Interrupt {
+    raised: Rc<Cell<bool>>,
+    /// Extra source polled alongside the flag, for hosts whose flag lives
+    /// elsewhere (such as shared memory written by another thread). Returns
+    /// whether an interrupt was requested, clearing the request.
+    probe: Rc<RefCell<Option<Box<dyn Fn() -> bool>>>>,
+}
+
+impl Interrupt {
+    pub fn raise(&self) {
+        self.raised.set(true);
+    }
+
+    pub fn set_probe(&self, probe: impl Fn() -> bool + 'static) {
+        *self.probe.borrow_mut() = Some(Box::new(probe));
+    }
+
+    /// Whether an interrupt was requested since the last check. Both sources
+    /// are consumed so neither request lingers into the next run.
+    fn take(&self) -> bool {
+        let raised = self.raised.replace(false);
+        let probed = self.probe.borrow().as_ref().is_some_and(|probe| probe());
+        return raised || probed;
+    }
+}
+
+/// Host objects that scripts hold by reference, keyed by the id inside gart's opaque values.
+///
+/// Released slots are reused, but an id also records its slot's generation, so
+/// a script still holding a released id gets nothing rather than the new object.
+pub struct Handles<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+struct Slot<T> {
+    generation: u16,
+    value: Option<T>,
+}
+
+impl<T> Default for Handles<T> {
+    fn default() -> Self {
+        return Self { slots: vec![], free: vec![] };
+    }
+}
+
+impl<T> Handles<T> {
+    pub fn insert(&mut self, value: T) -> u64 {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot { generation: 0, value: None });
+                self.slots.len() - 1
+            },
+        };
+        self.slots[index].value = Some(value);
+        // Ids stay below 2^53 so JS can hold them as numbers.
+        return ((self.slots[index].generation as u64) << 32) | index as u64;
+    }
+    pub fn get(&self, id: u64) -> Option<&T> {
+        return self.slot(id).and_then(|slot| slot.value.as_ref());
+    }
+    /// Drops the entry for `id`, returning it if `id` was still held.
+    pub fn release(&mut self, id: u64) -> Option<T> {
+        let index = (id & u32::MAX as u64) as usize;
+        self.slot(id)?;
+        let slot = &mut self.slots[index];
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index);
+        return Some(value);
+    }
+
+    fn slot(&self, id: u64) -> Option<&Slot<T>> {
+        let (generation, index) = (id >> 32, (id & u32::MAX as u64) as usize);
+        return self.slots.get(index).filter(|slot| slot.generation as u64 == generation);
+    }
+}
+
+/// Instructions run between checks of the clock and the interrupt flag; both
+/// can cost a host call, so they are not done on every step.
+pub const CHECK_INTERVAL: u64 = 1024;
+
+/// Why the VM stopped and handed control back to the host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    /// Stopped after a single step, with more left to run.
+    Running,
+    Finished,
+    Errored(Fault),
+    /// A native is awaiting a promise; the VM cannot continue until it settles.
+    Suspended,
+    BreakpointHit { line: usize },
+    /// A bounded run used up its instruction or time budget.
+    BudgetExhausted,
+    /// The host interrupted the run.
+    Cancelled,
+}
+
+/// Result of driving the VM, before it is handed to the host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    pub state: State,
+    /// Instructions executed while producing this outcome.
+    pub steps: u64,
+    /// Milliseconds spent, for time-sliced runs.
+    pub elapsed: Option<f64>,
+}
+
+impl Outcome {
+    pub fn new(state: State) -> Self {
+        return Self {
+            state,
+            steps: 0,
+            elapsed: None,
+        };
+    }
+    pub fn successful() -> Self {
+        return Self::new(State::Finished);
+    }
+    pub fn runtime_err(err: Fault) -> Self {
+        return Self::new(State::Errored(err));
+    }
+    pub fn unfinished() -> Self {
+        return Self::new(State::Running);
+    }
+    pub fn exhausted() -> Self {
+        return Self::new(State::BudgetExhausted);
+    }
+    pub fn awaiting() -> Self {
+        return Self::new(State::Suspended);
+    }
+    pub fn with_steps(self, steps: u64) -> Self {
+        return Self { steps, ..self };
+    }
+    pub fn with_elapsed(self, elapsed: f64) -> Self {
+        return Self { elapsed: Some(elapsed), ..self };
+    }
+
+    /// Whether the program has stopped for good, successfully or not.
+    pub fn finished(&self) -> bool {
+        return matches!(self.state, State::Finished | State::Errored(_));
+    }
+    pub fn error(&self) -> Option<&Fault> {
+        return match &self.state {
+            State::Errored(fault) => Some(fault),
+            _ => None,
+        };
+    }
+}
+
+/// The parts of the interpreter the VM drives.
+///
+/// Implemented for gart's `Interpreter`; tests substitute a scripted engine.
+pub trait Engine {
+    /// Executes one instruction, returning whether there is more to run.
+    fn step(&mut self) -> Result<bool, Fault>;
+    /// Sets up a call to `callee` once the program has finished; later steps run it.
+    fn begin_call(&mut self, callee: Value, args: Vec<Value>) -> Result<(), Fault>;
+    /// The value produced by the program or call that finished last.
+    fn completion(&self) -> Value;
+    /// Replaces the placeholder the last native call returned with its real result.
+    fn resume_with(&mut self, value: Value);
+    /// Looks up a global variable defined by the script.
+    fn global(&self, name: &str) -> Option<Value>;
+    /// Assigns a global variable, defining it if the script has not.
+    fn set_global(&mut self, name: &str, value: Value);
+    /// Every global variable, in definition order.
+    fn globals(&self) -> Vec<(String, Value)>;
+    /// Where the instruction about to execute came from, if anywhere.
+    fn location(&self) -> Option<Location>;
+    /// How many call frames are active, the top-level script included.
+    fn depth(&self) -> usize;
+    /// The active calls, innermost first.
+    fn frames(&self) -> Vec<Frame>;
+    /// The local variables in scope in call `frame` (0 is innermost), named as
+    /// declared; `None` if there is no such call.
+    fn locals(&self, frame: usize) -> Option<Vec<(String, Value)>>;
+    /// The variables call `frame`'s function captured from enclosing ones.
+    fn upvalues(&self, frame: usize) -> Option<Vec<(String, Value)>>;
+    /// Compiles `source` as an expression in the scope of call `frame` and runs
+    /// it to completion, leaving the program itself as it was.
+    fn evaluate(&mut self, frame: usize, source: &str) -> Result<Value, EvalError>;
+}
+
+impl Engine for Interpreter {
+    fn step(&mut self) -> Result<bool, Fault> {
+        return Interpreter::step(self).map_err(Fault::from);
+    }
+    fn begin_call(&mut self, callee: Value, args: Vec<Value>) -> Result<(), Fault> {
+        return Interpreter::begin_call(self, callee, args).map_err(Fault::from);
+    }
+    fn completion(&self) -> Value {
+        return self.last_value();
+    }
+    fn resume_with(&mut self, value: Value) {
+        self.replace_top(value);
+    }
+    fn global(&self, name: &str) -> Option<Value> {
+        return self.get_global(name);
+    }
+    fn set_global(&mut self, name: &str, value: Value) {
+        Interpreter::set_global(self, name, value);
+    }
+    fn globals(&self) -> Vec<(String, Value)> {
+        return Interpreter::globals(self);
+    }
+    fn location(&self) -> Option<Location> {
+        return self.current_span().map(Location::from);
+    }
+    fn depth(&self) -> usize {
+        return self.frame_count();
+    }
+    fn frames(&self) -> Vec<Frame> {
+        return self.call_frames().into_iter().map(Frame::from).collect();
+    }
+    fn locals(&self, frame: usize) -> Option<Vec<(String, Value)>> {
+        return self.frame_locals(frame);
+    }
+    fn upvalues(&self, frame: usize) -> Option<Vec<(String, Value)>> {
+        return self.frame_upvalues(frame);
+    }
+    fn evaluate(&mut self, frame: usize, source: &str) -> Result<Value, EvalError> {
+        return Interpreter::evaluate(self, frame, source).map_err(EvalError::from);
+    }
+}
+
+/// When a breakpoint set on a line takes effect.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Breakpoint {
+    /// An expression that must be truthy for the breakpoint to count as hit.
+    pub condition: Option<String>,
+    /// Stops only from this many hits onwards.
+    pub hit_count: Option<u64>,
+    /// Makes the breakpoint a logpoint: instead of stopping, the message is
+    /// sent to the log handler with each `{expression}` replaced by its value.
+    pub log_message: Option<String>,
+}
+
+pub struct Vm<E: Engine = Interpreter> {
+    engine: E,
+    link: HostLink,
+    /// Whether the engine has nothing left to run.
+    finished: bool,
+    /// Completion callback of the host call currently running, if any.
+    current_call: Option<Box<dyn FnOnce(Result<Value, Fault>)>>,
+    /// Once set, the VM refuses to run and keeps reporting this error until it is rebuilt.
+    failed: Option<Fault>,
+    /// Instructions executed over the VM's lifetime.
+    executed: u64,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    /// How often each breakpoint has been hit since it was set.
+    hits: BTreeMap<usize, u64>,
+    log: Option<Box<dyn Fn(usize, &str)>>,
+    /// The line execution was last seen on; breakpoints trigger when it changes.
+    line: Option<usize>,
+    /// Where the last instruction executed began. Natives fail part-way through
+    /// an instruction, but only surface once gart has moved on to the next.
+    step_start: Option<Location>,
+}
+
+impl<E: Engine> Vm<E> {
+    pub fn new(engine: E, link: HostLink) -> Self {
+        return Self {
+            engine,
+            link,
+            finished: false,
+            current_call: None,
+            failed: None,
+            executed: 0,
+            breakpoints: BTreeMap::new(),
+            hits: BTreeMap::new(),
+            log: None,
+            line: None,
+            step_start: None,
+        };
+    }
+
+    /// The error that stopped the VM, if any.
+    pub fn error(&self) -> Option<&Fault> {
+        return self.failed.as_ref();
+    }
+
+    /// The value the script (or the last host call) finished with: its last
+    /// evaluated top-level expression or explicit `return`.
+    pub fn completion(&self) -> Option<Value> {
+        if !self.finished || self.failed.is_some() {
+            return None;
+        }
+        return Some(self.engine.completion());
+    }
+
+    /// Whether the script and every queued call have run to completion.
+    pub fn is_idle(&self) -> bool {
+        return self.finished && self.link.calls.is_empty();
+    }
+
+    /// Runs `callee` to completion right away. Only valid while the VM is idle;
+    /// otherwise the host should queue the call on the `HostLink` instead.
+    pub fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, Fault> {
+        let result = Rc::new(RefCell::new(None));
+        let slot = result.clone();
+        let outcome = self.start_call(callee, args, Box::new(move |returned| *slot.borrow_mut() = Some(returned)))?;
+        return result.borrow_mut().take().unwrap_or_else(|| Err(call_stopped(&outcome.state)));
+    }
+
+    /// Starts `callee` right away and runs it as far as it goes, handing its
+    /// result to `done`. Usually that is to completion, but a call that stops
+    /// early (e.g. awaiting a promise) only calls `done` once the VM is resumed
+    /// and the call returns. Refused unless the VM is idle, in which case `done`
+    /// is dropped uncalled. An interrupt pending beforehand cancels the call
+    /// before it begins.
+    pub fn start_call(
+        &mut self,
+        callee: Value,
+        args: Vec<Value>,
+        done: Box<dyn FnOnce(Result<Value, Fault>)>,
+    ) -> Result<Outcome, Fault> {
+        if let Some(fault) = &self.failed {
+            return Err(fault.clone());
+        }
+        if !self.finished && self.executed == 0 {
+            return Err(Fault::new("The script has not run yet; run it before calling its functions."));
+        }
+        if !self.is_idle() {
+            return Err(Fault::new("The VM is still running; the call must wait until it finishes."));
+        }
+
+        self.link.calls.push(PendingCall { callee, args, done });
+        let outcome = self.interpret();
+        // An interrupt raised beforehand stops the run before the call begins;
+        // withdraw it rather than leave it queued for whoever resumes next.
+        if outcome.state == State::Cancelled && self.current_call.is_none()
+            && let Some(call) = self.link.calls.pop()
+        {
+            (call.done)(Err(Fault::new("The call was interrupted before it started.")));
+        }
+        return Ok(outcome);
+    }
+
+    pub fn global(&self, name: &str) -> Result<Value, Fault> {
+        return self.engine.global(name).ok_or_else(|| undefined_variable(name));
+    }
+
+    /// Assigns an existing global; use `define_global` to create one.
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), Fault> {
+        if self.engine.global(name).is_none() {
+            return Err(undefined_variable(name));
+        }
+        self.engine.set_global(name, value);
+        return Ok(());
+    }
+
+    /// Assigns a global, creating it if the script never defined it.
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.engine.set_global(name, value);
+    }
+
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        return self.engine.globals();
+    }
+
+    /// Calls the global function `name`, which the script must already have defined.
+    pub fn call_global(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Fault> {
+        let callee = self.function(name)?;
+        return self.call(callee, args);
+    }
+
+    /// The global `name`, for calling from the host.
+    pub fn function(&self, name: &str) -> Result<Value, Fault> {
+        return self.engine.global(name)
+            .ok_or_else(|| Fault::new(format!("Undefined function '{}'.", name)));
+    }
+
+    pub fn interpret(&mut self) -> Outcome {
+        return self.drive(|_, _| true);
+    }
+
+    /// Runs until the program finishes or `max_steps` instructions have executed.
+    pub fn run_for(&mut self, max_steps: u64) -> Outcome {
+        return self.drive(|_, steps| steps < max_steps);
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        return self.engine.location();
+    }
+
+    /// The active calls, innermost first; after a runtime error, the calls that
+    /// were active when it happened.
+    pub fn call_stack(&self) -> Vec<Frame> {
+        if let Some(fault) = &self.failed {
+            return fault.stack.clone();
+        }
+        return self.engine.frames();
+    }
+
+    /// The local variables of call `frame` of `call_stack`. Only available while
+    /// the program is paused: a runtime error unwinds every frame, so afterwards
+    /// this fails even though `call_stack` still lists them.
+    pub fn frame_locals(&self, frame: usize) -> Result<Vec<(String, Value)>, Fault> {
+        return self.variables(frame, self.engine.locals(frame));
+    }
+
+    /// The variables call `frame` captured, with the same limits as `frame_locals`.
+    pub fn frame_upvalues(&self, frame: usize) -> Result<Vec<(String, Value)>, Fault> {
+        return self.variables(frame, self.engine.upvalues(frame));
+    }
+
+    fn variables(&self, frame: usize, read: Option<Vec<(String, Value)>>) -> Result<Vec<(String, Value)>, Fault> {
+        if self.failed.is_some() {
+            return Err(Fault::new("Variables are not available after a runtime error."));
+        }
+        return read.ok_or_else(|| Fault::new(format!("No call frame {}.", frame)));
+    }
+
+    /// Evaluates the expression `source` where call `frame` is paused, e.g. for
+    /// a watch panel. The program carries on as if nothing had happened.
+    pub fn evaluate_in_frame(&mut self, frame: usize, source: &str) -> Result<Value, EvalError> {
+        if frame >= self.engine.depth() {
+            return Err(EvalError::Runtime(Fault::new(format!("No call frame {}.", frame))));
+        }
+        // Natives the expression calls report through the same slots as the
+        // program's, so they must neither leak an error into its next step nor
+        // replace a promise it is already waiting on.
+        self.link.suspension.block();
+        let result = self.engine.evaluate(frame, source);
+        let refused = self.link.suspension.unblock();
+        if let Some(err) = self.link.native_errors.take() {
+            return Err(EvalError::Runtime(Fault::from(err)));
+        }
+        if refused {
+            return Err(EvalError::Runtime(Fault::new("Expressions cannot wait for promises.")));
+        }
+        return result;
+    }
+
+    /// Runs until execution reaches a different source line, in any frame.
+    pub fn step_line(&mut self) -> Outcome {
+        let line = self.line();
+        return self.step_until(|engine| line_of(engine) != line);
+    }
+
+    /// Runs until execution reaches a different line or enters or leaves a call.
+    pub fn step_into(&mut self) -> Outcome {
+        let (line, depth) = (self.line(), self.engine.depth());
+        return self.step_until(|engine| engine.depth() != depth || line_of(engine) != line);
+    }
+
+    /// Runs until execution reaches a different line without descending into
+    /// calls made from this one.
+    pub fn step_over(&mut self) -> Outcome {
+        let (line, depth) = (self.line(), self.engine.depth());
+        return self.step_until(|engine| {
+            engine.depth() < depth || (engine.depth() == depth && line_of(engine) != line)
+        });
+    }
+
+    /// Runs until the current call returns.
+    pub fn step_out(&mut self) -> Outcome {
+        let depth = self.engine.depth();
+        return self.step_until(|engine| engine.depth() < depth);
+    }
+
+    /// Executes at least one instruction, then runs until `arrived` holds or a
+    /// breakpoint stops execution first.
+    fn step_until(&mut self, arrived: impl Fn(&E) -> bool) -> Outcome {
+        let outcome = self.drive(|engine, steps| steps == 0 || !arrived(engine));
+        self.track_line();
+        return match outcome.state {
+            State::BudgetExhausted => Outcome::unfinished().with_steps(outcome.steps),
+            _ => outcome,
+        };
+    }
+
+    fn line(&self) -> Option<usize> {
+        return line_of(&self.engine);
+    }
+
+    pub fn step(&mut self) -> Outcome {
+        let start = self.executed;
+        let outcome = self.advance();
+        // The line stepped onto counts as reached, so continuing from it does
+        // not stop straight away at a breakpoint there.
+        self.track_line();
+        return outcome.with_steps(self.executed - start);
+    }
+
+    /// Sets or replaces the breakpoint on `line`, starting its hit count afresh.
+    pub fn set_breakpoint(&mut self, line: usize, breakpoint: Breakpoint) {
+        self.breakpoints.insert(line, breakpoint);
+        self.hits.remove(&line);
+    }
+    pub fn clear_breakpoint(&mut self, line: usize) {
+        self.breakpoints.remove(&line);
+        self.hits.remove(&line);
+    }
+    pub fn clear_all_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.hits.clear();
+    }
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> + '_ {
+        return self.breakpoints.iter().map(|(line, breakpoint)| (*line, breakpoint));
+    }
+
+    /// Receives logpoint messages along with the line they were logged from.
+    pub fn set_log_handler(&mut self, log: impl Fn(usize, &str) + 'static) {
+        self.log = Some(Box::new(log));
+    }
+
+    /// Runs until the program finishes or `millis` have passed on `clock`,
+    /// which is only consulted every `CHECK_INTERVAL` instructions.
+    pub fn run_for_millis(&mut self, millis: f64, clock: impl Fn() -> f64) -> Outcome {
+        let start = clock();
+        let outcome = self.drive(|_, steps| steps == 0 || steps % CHECK_INTERVAL != 0 || clock() - start < millis);
+        return outcome.with_elapsed(clock() - start);
+    }
+
+    /// Runs until the program finishes, or stops with `BudgetExhausted` as soon
+    /// as `keep_going` (given the engine and the instructions executed so far)
+    /// returns false.
+    fn drive(&mut self, mut keep_going: impl FnMut(&E, u64) -> bool) -> Outcome {
+        let start = self.executed;
+        loop {
+            let steps = self.executed - start;
+            if steps % CHECK_INTERVAL == 0 && self.link.interrupt.take() {
+                return Outcome::new(State::Cancelled).with_steps(steps);
+            }
+            if !keep_going(&self.engine, steps) {
+                return Outcome::exhausted().with_steps(self.executed - start);
+            }
+            if let Some(line) = self.track_line() && self.breaks_at(line) {
+                return Outcome::new(State::BreakpointHit { line }).with_steps(steps);
+            }
+            let outcome = self.advance();
+            if outcome.finished() || outcome.state == State::Suspended {
+                return outcome.with_steps(self.executed - start);
+            }
+        }
+    }
+
+    /// Whether the breakpoint on `line`, if any, stops execution now. Logpoints
+    /// log here and never stop.
+    fn breaks_at(&mut self, line: usize) -> bool {
+        let Some(breakpoint) = self.breakpoints.get(&line).cloned() else {
+            return false;
+        };
+        // A condition that fails to evaluate stops, so the mistake gets noticed.
+        if let Some(condition) = &breakpoint.condition
+            && let Ok(value) = self.evaluate(condition)
+            && !truthy(&value)
+        {
+            return false;
+        }
+        let hits = self.hits.entry(line).or_default();
+        *hits += 1;
+        if breakpoint.hit_count.is_some_and(|threshold| *hits < threshold) {
+            return false;
+        }
+        if let Some(template) = &breakpoint.log_message {
+            let message = self.interpolate(template);
+            if let Some(log) = &self.log {
+                log(line, &message);
+            }
+            return false;
+        }
+        return true;
+    }
+
+    /// Replaces each `{expression}` in `template` with its value.
+    fn interpolate(&mut self, template: &str) -> String {
+        let mut message = String::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            let Some(len) = rest[open..].find('}') else {
+                break;
+            };
+            message.push_str(&rest[..open]);
+            match self.evaluate(&rest[open + 1..open + len]) {
+                Ok(value) => message.push_str(&display(&value)),
+                Err(err) => message.push_str(&format!("<{}>", err)),
+            }
+            rest = &rest[open + len + 1..];
+        }
+        message.push_str(rest);
+        return message;
+    }
+
+    fn evaluate(&mut self, source: &str) -> Result<Value, EvalError> {
+        return self.evaluate_in_frame(0, source);
+    }
+
+    /// Records the line about to execute, returning it if execution just moved there.
+    fn track_line(&mut self) -> Option<usize> {
+        let line = self.line();
+        if line == self.line {
+            return None;
+        }
+        self.line = line;
+        return line;
+    }
+
+    /// Executes at most one instruction, starting the next queued call if the engine is idle.
+    fn advance(&mut self) -> Outcome {
+        if let Some(fault) = &self.failed {
+            return Outcome::runtime_err(fault.clone());
+        }
+        if self.link.suspension.is_pending() {
+            return Outcome::awaiting();
+        }
+        match self.link.suspension.take_settled() {
+            Some(Ok(value)) => self.engine.resume_with(value),
+            Some(Err(fault)) => {
+                let fault = self.at_last_step(fault);
+                return self.fail(fault);
+            },
+            None => {},
+        }
+        if self.finished {
+            match self.link.calls.pop() {
+                Some(call) => {
+                    if let Err(fault) = self.engine.begin_call(call.callee, call.args) {
+                        // The callee was never entered, so only the caller hears about it.
+                        (call.done)(Err(fault));
+                        return self.settled();
+                    }
+                    self.finished = false;
+                    self.current_call = Some(call.done);
+                },
+                None => return Outcome::successful(),
+            }
+        }
+
+        self.step_start = self.engine.location();
+        let stepped = self.engine.step();
+        self.executed += 1;
+        // A native that failed during this step takes precedence: anything the
+        // script did afterwards was working with the placeholder it returned.
+        let result = match self.link.native_errors.take() {
+            Some(err) => Err(self.at_last_step(Fault::from(err))),
+            None => stepped,
+        };
+
+        return match result {
+            Ok(true) if self.link.suspension.is_pending() => Outcome::awaiting(),
+            Ok(true) => Outcome::unfinished(),
+            Ok(false) => {
+                // Nothing is left to receive a result the program stopped waiting for.
+                self.link.suspension.abandon();
+                self.finished = true;
+                if let Some(done) = self.current_call.take() {
+                    done(Ok(self.engine.completion()));
+                }
+                self.settled()
+            },
+            Err(fault) => self.fail(fault),
+        };
+    }
+
+    /// Attributes a native's `fault` to the instruction that called it.
+    fn at_last_step(&self, mut fault: Fault) -> Fault {
+        // Errors from natives leave the stack standing, so it can be read here.
+        fault.stack = self.engine.frames();
+        if let Some(innermost) = fault.stack.first_mut() {
+            innermost.location = self.step_start;
+        }
+        return fault;
+    }
+
+    fn fail(&mut self, mut fault: Fault) -> Outcome {
+        // Script errors arrive with the stack gart saw before unwinding.
+        if fault.stack.is_empty() {
+            fault.stack = self.engine.frames();
+        }
+        self.link.suspension.abandon();
+        self.failed = Some(fault.clone());
+        if let Some(done) = self.current_call.take() {
+            done(Err(fault.clone()));
+        }
+        return Outcome::runtime_err(fault);
+    }
+
+    /// Outcome once the engine has nothing running: finished unless calls are still queued.
+    fn settled(&self) -> Outcome {
+        if self.link.calls.is_empty() { Outcome::successful() }
+        else { Outcome::unfinished() }
+    }
+
+    /// Fails the host call in progress, if any, because the VM is going away.
+    pub fn abandon_call(&mut self) {
+        if let Some(done) = self.current_call.take() {
+            done(Err(Fault::new(DROPPED)));
+        }
+    }
+}
+
+impl<E: Engine> Drop for Vm<E> {
+    fn drop(&mut self) {
+        // A caller awaiting a call that stopped part-way would otherwise never hear back.
+        self.abandon_call();
+    }
+}
+
+pub const DROPPED: &str = "The VM that owns this function has been dropped.";
+
+/// Why a host call that stopped in `state` has not returned.
+pub fn call_stopped(state: &State) -> Fault {
+    return Fault::new(match state {
+        State::Errored(fault) => return fault.clone(),
+        State::Suspended => "The call is awaiting a promise; it will finish once the VM resumes.".to_owned(),
+        State::BreakpointHit { line } => format!("The call stopped at a breakpoint on line {}; it will finish once the VM resumes.", line),
+        State::Cancelled => "The call was interrupted; it will finish once the VM resumes.".to_owned(),
+        _ => "The call did not run.".to_owned(),
+    });
+}
+
+fn undefined_variable(name: &str) -> Fault {
+    return Fault::new(format!("Undefined variable '{}'.", name));
+}
+
+fn line_of(engine: &impl Engine) -> Option<usize> {
+    return engine.location().map(|location| location.line);
+}
+
+/// Whether scripts treat `value` as true in a condition.
+fn truthy(value: &Value) -> bool {
+    return !matches!(value, Value::Null | Value::Bool(false));
+}
+
+/// How `value` reads in a log message: primitives as scripts would print them,
+/// anything else by its type.
+pub fn display(value: &Value) -> String {
+    return match value {
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::String(s) => s.to_string(),
+        Value::Null => "null".to_owned(),
+        _ => format!("<{}>", type_name(value)),
+    };
+}
+
+/// The name scripts would use for the type of `value`, for error messages.
+pub fn type_name(value: &Value) -> &'static str {
+    return match value {
+        Value::Number(_) => "number",
+        Value::Bool(_) => "bool",
+        Value::String(_) => "string",
+        Value::Null => "null",
+        Value::List(_) => "list",
+        Value::Map(_) => "map",
+        Value::Opaque(_) => "handle",
+        Value::Closure(_) => "function",
+        Value::NativeFunction(_) => "native function",
+        _ => "object",
+    };
+}
+
+/// Builds the `time()` native, reading seconds from `clock` (which returns milliseconds).
+pub fn time_native(clock: fn() -> f64) -> NativeFunction {
+    return NativeFunction {
+        name: "time".to_owned(),
+        arity: 0,
+        function: Box::new(move |_: &[Value]| Value::Number(clock() / 1000.0)),
+    };
+}
+
+/// Compiles `source` with the host's natives plus the built-in ones.
+///
+/// `link` must be the one the host's natives were built to report into.
+pub fn compile(
+    source: &str,
+    mut natives: Vec<NativeFunction>,
+    link: HostLink,
+    clock: fn() -> f64,
+) -> Result<Vm, Vec<Diagnostic>> {
+    natives.push(time_native(clock));
+
+    return match Interpreter::new(source.to_owned(), natives) {
+        Ok(interpreter) => Ok(Vm::new(interpreter, link)),
+        Err(compiler_errors) => Err(compiler_errors.into_iter().map(Diagnostic::from).collect()),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Replays a fixed sequence of step results. Calls take two steps and
+    /// return their first argument; calling `null` fails.
+    struct Scripted {
+        steps: VecDeque<Result<bool, Fault>>,
+        completion: Value,
+        globals: Vec<(String, Value)>,
+        /// When set, the next step behaves like a native returning a promise.
+        suspend_next: Option<Suspension>,
+        ticket: u64,
+        /// The source line and call depth of each remaining step.
+        lines: VecDeque<(usize, usize)>,
+    }
+
+    impl Scripted {
+        fn new(steps: Vec<Result<bool, Fault>>) -> Self {
+            return Self {
+                steps: steps.into(),
+                completion: Value::Null,
+                globals: vec![("update".to_owned(), callee())],
+                suspend_next: None,
+                ticket: 0,
+                lines: VecDeque::new(),
+            };
+        }
+    }
+
+    impl Engine for Scripted {
+        fn step(&mut self) -> Result<bool, Fault> {
+            if let Some(ticket) = self.suspend_next.take().and_then(|suspension| suspension.suspend()) {
+                self.ticket = ticket;
+            }
+            let result = self.steps.pop_front().unwrap_or(Ok(false));
+            // Like gart, a failing instruction unwinds every frame.
+            if result.is_ok() {
+                self.lines.pop_front();
+            } else {
+                self.lines.clear();
+            }
+            return result;
+        }
+        fn begin_call(&mut self, callee: Value, args: Vec<Value>) -> Result<(), Fault> {
+            if let Value::Null = callee {
+                return Err(fault("Can only call functions and classes."));
+            }
+            self.steps.extend([Ok(true), Ok(false)]);
+            self.completion = args.into_iter().next().unwrap_or(Value::Null);
+            return Ok(());
+        }
+        fn completion(&self) -> Value {
+            return self.completion.clone();
+        }
+        fn resume_with(&mut self, value: Value) {
+            self.completion = value;
+        }
+        fn global(&self, name: &str) -> Option<Value> {
+            return self.globals.iter().find(|(global, _)| global == name).map(|(_, value)| value.clone());
+        }
+        fn set_global(&mut self, name: &str, value: Value) {
+            match self.globals.iter_mut().find(|(global, _)| global == name) {
+                Some((_, held)) => *held = value,
+                None => self.globals.push((name.to_owned(), value)),
+            }
+        }
+        fn globals(&self) -> Vec<(String, Value)> {
+            return self.globals.clone();
+        }
+        fn location(&self) -> Option<Location> {
+            return self.lines.front().map(|&(line, _)| Location { line, column: 1, len: 1 });
+        }
+        fn depth(&self) -> usize {
+            return self.lines.front().map_or(1, |&(_, depth)| depth);
+        }
+        /// Only the innermost frame knows where it is.
+        fn frames(&self) -> Vec<Frame> {
+            let Some(&(_, depth)) = self.lines.front() else {
+                return vec![];
+            };
+            return (1..=depth).rev().map(|level| Frame {
+                name: if level == 1 { "script".to_owned() } else { format!("f{}", level) },
+                location: if level == depth { self.location() } else { None },
+                offset: level,
+            }).collect();
+        }
+        fn locals(&self, frame: usize) -> Option<Vec<(String, Value)>> {
+            return (frame < self.frames().len()).then(Vec::new);
+        }
+        fn upvalues(&self, frame: usize) -> Option<Vec<(String, Value)>> {
+            return (frame < self.frames().len()).then(Vec::new);
+        }
+        /// Expressions are just global names, which may await like a step.
+        fn evaluate(&mut self, _frame: usize, source: &str) -> Result<Value, EvalError> {
+            if let Some(ticket) = self.suspend_next.take().and_then(|suspension| suspension.suspend()) {
+                self.ticket = ticket;
+            }
+            return self.global(source).ok_or_else(|| EvalError::Runtime(undefined_variable(source)));
+        }
+    }
+
+    fn fault(message: &str) -> Fault {
+        return Fault::new(message);
+    }
+
+    fn vm(steps: Vec<Result<bool, Fault>>) -> Vm<Scripted> {
+        return Vm::new(Scripted::new(steps), HostLink::default());
+    }
+
+    /// A program running one instruction on each of `lines`, all at the top level.
+    fn on_lines(lines: &[usize]) -> Vm<Scripted> {
+        let frames: Vec<_> = lines.iter().map(|&line| (line, 1)).collect();
+        return in_frames(&frames);
+    }
+
+    /// A program running one instruction on each line, at the given call depth.
+    fn in_frames(lines: &[(usize, usize)]) -> Vm<Scripted> {
+        let mut steps = vec![Ok(true); lines.len() - 1];
+        steps.push(Ok(false));
+        let mut vm = vm(steps);
+        vm.engine.lines = lines.iter().copied().collect();
+        return vm;
+    }
+
+    fn callee() -> Value {
+        return Value::String("update".into());
+    }
+
+    fn number(value: Result<Value, Fault>) -> Option<f64> {
+        return match value {
+            Ok(Value::Number(n)) => Some(n),
+            _ => None,
+        };
+    }
+
+    fn thrown(native: &str) -> NativeError {
+        return NativeError {
+            native: native.to_owned(),
+            name: Some("TypeError".to_owned()),
+            message: "x is undefined".to_owned(),
+            stack: None,
+        };
+    }
+
+    #[test]
+    fn compiler_errors_keep_their_span() {
+        let diagnostic = Diagnostic::from(CompilerError {
+            line: 3,
+            start: 7,
+            len: 2,
+            message: "Expect ';'.".to_owned(),
+        });
+
+        assert_eq!(diagnostic, Diagnostic { line: 3, start: 7, len: 2, message: "Expect ';'.".to_owned() });
+    }
+
+    #[test]
+    fn runtime_errors_keep_their_trace() {
+        let frame = |name: &str, line| FrameInfo {
+            name: name.to_owned(),
+            span: Some(Span { line, start: 4, len: 3 }),
+            ip: 12,
+        };
+        let fault = Fault::from(RuntimeError {
+            message: "Operands must be numbers.".to_owned(),
+            trace: vec![frame("area", 7), frame("script", 20)],
+        });
+
+        assert_eq!(fault.location(), Some(Location { line: 7, column: 4, len: 3 }));
+        assert_eq!(fault.stack[1].name, "script");
+    }
+
+    #[test]
+    fn time_reports_seconds() {
+        let native = time_native(|| 2500.0);
+
+        assert_eq!(native.name, "time");
+        assert_eq!(native.arity, 0);
+        assert!(matches!((native.function)(&[]), Value::Number(n) if n == 2.5));
+    }
+
+    #[test]
+    fn type_names_match_script_vocabulary() {
+        assert_eq!(type_name(&Value::Number(1.0)), "number");
+        assert_eq!(type_name(&Value::String("hi".into())), "string");
+        assert_eq!(type_name(&Value::Null), "null");
+        assert_eq!(type_name(&Value::List(Rc::new(RefCell::new(vec![])))), "list");
+    }
+
+    #[test]
+    fn released_handles_do_not_resolve_to_their_successors() {
+        let mut handles = Handles::default();
+
+        let canvas = handles.insert("canvas");
+        let audio = handles.insert("audio");
+        assert_ne!(canvas, audio);
+        assert_eq!(handles.get(audio), Some(&"audio"));
+
+        assert_eq!(handles.release(canvas), Some("canvas"));
+        assert_eq!(handles.release(canvas), None);
+        let video = handles.insert("video");
+
+        assert_ne!(video, canvas);
+        assert_eq!(handles.get(canvas), None);
+        assert_eq!(handles.get(video), Some(&"video"));
+        assert_eq!(handles.get(video + 1), None);
+    }
+
+    #[test]
+    fn step_reports_progress() {
+        let mut vm = vm(vec![Ok(true), Ok(false)]);
+
+        assert_eq!(vm.step(), Outcome::unfinished().with_steps(1));
+        assert_eq!(vm.step(), Outcome::successful().with_steps(1));
+        assert_eq!(vm.step(), Outcome::successful());
+    }
+
+    #[test]
+    fn interpret_surfaces_runtime_errors() {
+        let mut vm = vm(vec![Ok(true), Err(fault("Operands must be numbers."))]);
+
+        assert_eq!(vm.interpret(), Outcome::runtime_err(fault("Operands must be numbers.")).with_steps(2));
+    }
+
+    #[test]
+    fn native_failure_stops_the_vm() {
+        let link = HostLink::default();
+        let mut vm = Vm::new(Scripted::new(vec![Ok(true), Ok(true), Ok(true)]), link.clone());
+
+        assert_eq!(vm.step().state, State::Running);
+        link.native_errors.raise(thrown("draw"));
+        let outcome = vm.step();
+
+        let fault = outcome.error().cloned().expect("native failure should surface");
+        assert_eq!(fault.message, "Native 'draw' threw TypeError: x is undefined");
+        assert_eq!(fault.native, Some(thrown("draw")));
+        assert!(outcome.finished());
+    }
+
+    #[test]
+    fn failed_vm_keeps_reporting_its_error() {
+        let mut vm = vm(vec![Err(fault("Undefined variable 'x'.")), Ok(true)]);
+
+        vm.step();
+
+        assert_eq!(vm.error(), Some(&fault("Undefined variable 'x'.")));
+        assert_eq!(vm.interpret(), Outcome::runtime_err(fault("Undefined variable 'x'.")));
+    }
+
+    #[test]
+    fn call_runs_to_completion_when_idle() {
+        let mut vm = vm(vec![Ok(false)]);
+        vm.interpret();
+
+        assert!(vm.is_idle());
+        assert_eq!(number(vm.call(callee(), vec![Value::Number(4.0)])), Some(4.0));
+        assert!(vm.is_idle());
+    }
+
+    #[test]
+    fn calls_that_await_a_promise_finish_when_the_vm_resumes() {
+        let link = HostLink::default();
+        let mut vm = Vm::new(Scripted::new(vec![Ok(false)]), link.clone());
+        let returned = Rc::new(RefCell::new(None));
+        let slot = returned.clone();
+
+        vm.interpret();
+        vm.engine.suspend_next = Some(link.suspension.clone());
+        let outcome = vm.start_call(callee(), vec![Value::Number(5.0)], Box::new(move |value| *slot.borrow_mut() = number(value)));
+
+        assert_eq!(outcome, Ok(Outcome::awaiting().with_steps(1)));
+        assert!(returned.borrow().is_none());
+        assert!(vm.call(callee(), vec![]).is_err());
+
+        link.suspension.settle(vm.engine.ticket, Ok(Value::Number(6.0)));
+        assert_eq!(vm.interpret(), Outcome::successful().with_steps(1));
+        assert_eq!(*returned.borrow(), Some(6.0));
+        assert!(vm.is_idle());
+    }
+
+    #[test]
+    fn dropping_the_vm_fails_the_call_in_progress() {
+        let link = HostLink::default();
+        let mut vm = Vm::new(Scripted::new(vec![Ok(false)]), link.clone());
+        let returned = Rc::new(RefCell::new(None));
+        let slot = returned.clone();
+
+        vm.interpret();
+        vm.engine.suspend_next = Some(link.suspension.clone());
+        vm.start_call(callee(), vec![], Box::new(move |value| *slot.borrow_mut() = value.err())).ok().unwrap();
+        assert!(returned.borrow().is_none());
+
+        drop(vm);
+        assert_eq!(*returned.borrow(), Some(fault(DROPPED)));
+    }
+
+    #[test]
+    fn interrupted_calls_are_withdrawn_before_they_start() {
+        let mut vm = vm(vec![Ok(false)]);
+        let returned = Rc::new(RefCell::new(None));
+        let slot = returned.clone();
+
+        vm.interpret();
+        vm.link.interrupt.raise();
+        let outcome = vm.start_call(callee(), vec![Value::Number(1.0)], Box::new(move |value| *slot.borrow_mut() = value.err()));
+
+        assert_eq!(outcome, Ok(Outcome::new(State::Cancelled)));
+        assert_eq!(*returned.borrow(), Some(fault("The call was interrupted before it started.")));
+        assert!(vm.is_idle());
+        assert_eq!(number(vm.call(callee(), vec![Value::Number(2.0)])), Some(2.0));
+    }
+
+    #[test]
+    fn calls_stopped_at_a_breakpoint_say_so() {
+        let mut vm = vm(vec![Ok(false)]);
+        vm.interpret();
+        vm.engine.lines = [(4, 2), (5, 2)].into();
+        vm.set_breakpoint(5, Breakpoint::default());
+
+        let returned = vm.call(callee(), vec![]);
+
+        assert_eq!(returned.err(), Some(fault("The call stopped at a breakpoint on line 5; it will finish once the VM resumes.")));
+        assert_eq!(vm.interpret(), Outcome::successful().with_steps(1));
+        assert!(vm.is_idle());
+    }
+
+    #[test]
+    fn call_is_refused_until_the_script_has_run() {
+        let mut vm = vm(vec![Ok(true), Ok(false)]);
+        assert_eq!(vm.call(callee(), vec![]).err(), Some(fault("The script has not run yet; run it before calling its functions.")));
+
+        vm.step();
+        assert_eq!(vm.call(callee(), vec![]).err(), Some(fault("The VM is still running; the call must wait until it finishes.")));
+    }
+
+    #[test]
+    fn queued_calls_run_after_the_script() {
+        let link = HostLink::default();
+        let mut vm = Vm::new(Scripted::new(vec![Ok(true), Ok(false)]), link.clone());
+        let returned = Rc::new(RefCell::new(None));
+        let slot = returned.clone();
+
+        vm.step();
+        link.calls.push(PendingCall {
+            callee: callee(),
+            args: vec![Value::Number(7.0)],
+            done: Box::new(move |value| *slot.borrow_mut() = number(value)),
+        });
+
+        assert_eq!(vm.step(), Outcome::unfinished().with_steps(1));
+        assert!(returned.borrow().is_none());
+        assert_eq!(vm.interpret(), Outcome::successful().with_steps(2));
+        assert_eq!(*returned.borrow(), Some(7.0));
+    }
+
+    #[test]
+    fn calling_a_non_function_reports_to_the_caller_only() {
+        let mut vm = vm(vec![Ok(false)]);
+        vm.interpret();
+
+        assert!(vm.call(Value::Null, vec![]).is_err());
+        assert_eq!(vm.error(), None);
+    }
+
+    #[test]
+    fn call_global_looks_up_the_function() {
+        let mut vm = vm(vec![Ok(false)]);
+        vm.interpret();
+
+        assert_eq!(number(vm.call_global("update", vec![Value::Number(0.5)])), Some(0.5));
+        assert_eq!(vm.call_global("draw", vec![]).err(), Some(fault("Undefined function 'draw'.")));
+    }
+
+    #[test]
+    fn set_global_refuses_unknown_names() {
+        let mut vm = vm(vec![]);
+
+        assert_eq!(vm.set_global("speed", Value::Number(2.0)), Err(fault("Undefined variable 'speed'.")));
+        assert!(vm.global("speed").is_err());
+
+        vm.define_global("speed", Value::Number(2.0));
+        assert!(vm.set_global("speed", Value::Number(3.0)).is_ok());
+        assert_eq!(number(vm.global("speed")), Some(3.0));
+        assert_eq!(vm.globals().iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["update", "speed"]);
+    }
+
+    #[test]
+    fn completion_is_only_available_once_finished() {
+        let mut vm = vm(vec![Ok(true), Ok(false)]);
+
+        vm.step();
+        assert!(vm.completion().is_none());
+        vm.step();
+        assert!(matches!(vm.completion(), Some(Value::Null)));
+    }
+
+    #[test]
+    fn run_for_stops_when_the_budget_is_spent() {
+        let mut vm = vm(vec![Ok(true), Ok(true), Ok(true), Ok(false)]);
+
+        assert_eq!(vm.run_for(3), Outcome::exhausted().with_steps(3));
+        assert_eq!(vm.run_for(3), Outcome::successful().with_steps(1));
+    }
+
+    #[test]
+    fn run_for_millis_checks_the_clock_between_batches() {
+        let mut vm = vm(vec![Ok(true); 5000]);
+        let now = std::cell::Cell::new(0.0);
+        let clock = || {
+            now.set(now.get() + 1.0);
+            now.get()
+        };
+
+        let outcome = vm.run_for_millis(2.5, clock);
+
+        assert_eq!(outcome.state, State::BudgetExhausted);
+        assert_eq!(outcome.steps, 3 * CHECK_INTERVAL);
+        assert_eq!(outcome.elapsed, Some(4.0));
+    }
+
+    #[test]
+    fn awaiting_native_suspends_until_settled() {
+        let link = HostLink::default();
+        let mut vm = Vm::new(Scripted::new(vec![Ok(true), Ok(false)]), link.clone());
+        vm.engine.suspend_next = Some(link.suspension.clone());
+
+        assert_eq!(vm.interpret(), Outcome::awaiting().with_steps(1));
+        assert_eq!(vm.interpret(), Outcome::awaiting());
+
+        link.suspension.settle(vm.engine.ticket, Ok(Value::Number(9.0)));
+        assert_eq!(vm.interpret(), Outcome::successful().with_steps(1));
+        assert!(matches!(vm.completion(), Some(Value::Number(n)) if n == 9.0));
+    }
+
+    #[test]
+    fn rejected_promise_fails_the_vm() {
+        let link = HostLink::default();
+        let mut vm = Vm::new(Scripted::new(vec![Ok(true), Ok(false)]), link.clone());
+        vm.engine.suspend_next = Some(link.suspension.clone());
+
+        assert_eq!(vm.step(), Outcome::awaiting().with_steps(1));
+        link.suspension.settle(vm.engine.ticket, Err(fault("Native 'load' threw: 404")));
+
+        assert_eq!(vm.step(), Outcome::runtime_err(fault("Native 'load' threw: 404")));
+        assert!(vm.error().is_some());
+    }
+
+    #[test]
+    fn stale_settlements_are_ignored() {
+        let suspension = Suspension::default();
+
+        let first = suspension.suspend().unwrap();
+        let _second = suspension.suspend().unwrap();
+        suspension.settle(first, Ok(Value::Null));
+
+        assert!(suspension.is_pending());
+    }
+
+    #[test]
+    fn interrupt_cancels_at_the_next_check() {
+        let mut vm = vm(vec![Ok(true); 3 * CHECK_INTERVAL as usize]);
+        vm.link.interrupt.raise();
+        assert_eq!(vm.interpret(), Outcome::new(State::Cancelled));
+        assert_eq!(vm.run_for(10), Outcome::exhausted().with_steps(10));
+    }
+
+    #[test]
+    fn interrupt_probe_is_polled_between_batches() {
+        let mut vm = vm(vec![Ok(true); 3 * CHECK_INTERVAL as usize]);
+        let polls = Rc::new(Cell::new(0));
+        let counter = polls.clone();
+        vm.link.interrupt.set_probe(move || {
+            counter.set(counter.get() + 1);
+            counter.get() == 2
+        });
+
+        let outcome = vm.interpret();
+
+        assert_eq!(outcome.state, State::Cancelled);
+        assert_eq!(outcome.steps, CHECK_INTERVAL);
+        assert_eq!(polls.get(), 2);
+    }
+
+    #[test]
+    fn breakpoints_stop_on_entering_their_line() {
+        let mut vm = on_lines(&[1, 2, 2, 3, 2]);
+        vm.set_breakpoint(2, Breakpoint::default());
+
+        assert_eq!(vm.interpret(), Outcome::new(State::BreakpointHit { line: 2 }).with_steps(1));
+        assert_eq!(vm.interpret(), Outcome::new(State::BreakpointHit { line: 2 }).with_steps(3));
+        assert_eq!(vm.interpret(), Outcome::successful().with_steps(1));
+    }
+
+    #[test]
+    fn stepping_onto_a_breakpoint_does_not_stop_the_next_run() {
+        let mut vm = on_lines(&[1, 2, 3]);
+        vm.set_breakpoint(2, Breakpoint::default());
+        vm.set_breakpoint(3, Breakpoint::default());
+
+        vm.step();
+        assert_eq!(vm.run_for(10), Outcome::new(State::BreakpointHit { line: 3 }).with_steps(1));
+
+        vm.clear_all_breakpoints();
+        assert!(vm.interpret().finished());
+    }
+
+    #[test]
+    fn conditional_breakpoints_stop_only_when_truthy() {
+        let mut vm = on_lines(&[1, 2, 3, 2]);
+        vm.define_global("ready", Value::Bool(false));
+        vm.set_breakpoint(2, Breakpoint { condition: Some("ready".to_owned()), ..Breakpoint::default() });
+
+        assert_eq!(vm.run_for(2), Outcome::exhausted().with_steps(2));
+        vm.define_global("ready", Value::Number(0.0));
+        assert_eq!(vm.interpret(), Outcome::new(State::BreakpointHit { line: 2 }).with_steps(1));
+    }
+
+    #[test]
+    fn hit_counts_delay_the_first_stop() {
+        let mut vm = on_lines(&[2, 3, 2, 3, 2]);
+        vm.set_breakpoint(2, Breakpoint { hit_count: Some(3), ..Breakpoint::default() });
+
+        assert_eq!(vm.interpret(), Outcome::new(State::BreakpointHit { line: 2 }).with_steps(4));
+    }
+
+    #[test]
+    fn logpoints_log_without_stopping() {
+        let mut vm = on_lines(&[1, 2, 3, 2]);
+        let logged = Rc::new(RefCell::new(vec![]));
+        let sink = logged.clone();
+        vm.set_log_handler(move |line, message| sink.borrow_mut().push((line, message.to_owned())));
+        vm.define_global("x", Value::Number(2.5));
+        vm.set_breakpoint(2, Breakpoint { log_message: Some("x={x}, y={y}".to_owned()), ..Breakpoint::default() });
+
+        assert!(vm.interpret().finished());
+        let expected = (2, "x=2.5, y=<Undefined variable 'y'.>".to_owned());
+        assert_eq!(*logged.borrow(), vec![expected.clone(), expected]);
+    }
+
+    #[test]
+    fn line_steps_follow_execution_into_calls() {
+        // Line 2 calls a function on line 8 that returns to line 2.
+        let program = [(1, 1), (2, 1), (8, 2), (8, 2), (2, 1), (3, 1)];
+
+        let mut vm = in_frames(&program);
+        assert_eq!(vm.step_line(), Outcome::unfinished().with_steps(1));
+        assert_eq!(vm.step_line(), Outcome::unfinished().with_steps(1));
+        assert_eq!(vm.location(), Some(Location { line: 8, column: 1, len: 1 }));
+
+        let mut vm = in_frames(&program);
+        vm.step_into();
+        assert_eq!(vm.step_over(), Outcome::unfinished().with_steps(4));
+        assert_eq!(vm.location(), Some(Location { line: 3, column: 1, len: 1 }));
+    }
+
+    #[test]
+    fn step_into_stops_on_calls_to_the_same_line() {
+        let mut vm = in_frames(&[(2, 1), (2, 2), (2, 1)]);
+
+        assert_eq!(vm.step_into(), Outcome::unfinished().with_steps(1));
+        assert_eq!(vm.step_line(), Outcome::successful().with_steps(2));
+    }
+
+    #[test]
+    fn step_out_runs_to_the_caller() {
+        let mut vm = in_frames(&[(1, 1), (8, 2), (9, 2), (9, 3), (10, 2), (2, 1), (3, 1)]);
+        vm.step();
+        vm.set_breakpoint(10, Breakpoint::default());
+
+        assert_eq!(vm.step_out(), Outcome::new(State::BreakpointHit { line: 10 }).with_steps(3));
+        assert_eq!(vm.step_out(), Outcome::unfinished().with_steps(1));
+        assert_eq!(vm.location(), Some(Location { line: 2, column: 1, len: 1 }));
+    }
+
+    #[test]
+    fn call_stack_survives_runtime_errors() {
+        let mut vm = in_frames(&[(1, 1), (8, 2), (9, 2)]);
+        vm.step();
+        vm.link.native_errors.raise(thrown("draw"));
+
+        vm.interpret();
+
+        let stack = vm.call_stack();
+        assert_eq!(stack.iter().map(|frame| frame.name.as_str()).collect::<Vec<_>>(), ["f2", "script"]);
+        assert_eq!(stack[0].location, Some(Location { line: 8, column: 1, len: 1 }));
+        assert_eq!(vm.error().map(|fault| &fault.stack), Some(&stack));
+    }
+
+    #[test]
+    fn variables_are_only_read_while_paused() {
+        let mut vm = in_frames(&[(1, 1), (8, 2), (9, 2)]);
+        vm.step();
+
+        assert!(vm.frame_locals(1).is_ok_and(|locals| locals.is_empty()));
+        assert_eq!(vm.frame_upvalues(2).err(), Some(fault("No call frame 2.")));
+
+        vm.link.native_errors.raise(thrown("draw"));
+        vm.interpret();
+
+        assert_eq!(vm.call_stack().len(), 2);
+        assert_eq!(vm.frame_locals(0).err(), Some(fault("Variables are not available after a runtime error.")));
+    }
+
+    #[test]
+    fn evaluation_leaves_the_program_alone() {
+        let mut vm = in_frames(&[(1, 1), (8, 2)]);
+        vm.define_global("x", Value::Number(4.0));
+        vm.step();
+
+        assert_eq!(number(vm.evaluate_in_frame(1, "x").map_err(|_| fault("failed"))), Some(4.0));
+        assert_eq!(vm.evaluate_in_frame(1, "y").err(), Some(EvalError::Runtime(undefined_variable("y"))));
+        assert_eq!(vm.evaluate_in_frame(2, "x").err(), Some(EvalError::Runtime(fault("No call frame 2."))));
+
+        vm.engine.suspend_next = Some(vm.link.suspension.clone());
+        assert_eq!(
+            vm.evaluate_in_frame(0, "x").err(),
+            Some(EvalError::Runtime(fault("Expressions cannot wait for promises.")))
+        );
+        assert_eq!(vm.interpret(), Outcome::successful().with_steps(1));
+    }
+
+    #[test]
+    fn evaluating_while_suspended_keeps_the_awaited_promise() {
+        let link = HostLink::default();
+        let mut vm = Vm::new(Scripted::new(vec![Ok(true), Ok(false)]), link.clone());
+        vm.engine.lines = [(3, 1), (4, 1)].into();
+        vm.define_global("x", Value::Number(1.0));
+        vm.engine.suspend_next = Some(link.suspension.clone());
+        assert_eq!(vm.interpret(), Outcome::awaiting().with_steps(1));
+        let ticket = vm.engine.ticket;
+
+        vm.engine.suspend_next = Some(link.suspension.clone());
+        assert_eq!(
+            vm.evaluate_in_frame(0, "x").err(),
+            Some(EvalError::Runtime(fault("Expressions cannot wait for promises.")))
+        );
+        assert_eq!(number(vm.evaluate_in_frame(0, "x").map_err(|_| fault("failed"))), Some(1.0));
+        assert!(link.suspension.is_pending());
+
+        link.suspension.settle(ticket, Ok(Value::Number(9.0)));
+        assert_eq!(vm.interpret(), Outcome::successful().with_steps(1));
+        assert!(matches!(vm.completion(), Some(Value::Number(n)) if n == 9.0));
+    }
+}
@@ -1,20 +1,163 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use gart::{NativeFunction, Value};
-use js_sys::{Array, Date};
+use js_sys::{Array, Atomics, Date, Int32Array, Object, Promise, Reflect};
 use wasm_bindgen::prelude::*;
-use gart::interpreter::Interpreter;
-use gart::interpreter::{CompilerError, RuntimeError};
+use crate::catalogue::{self, ErrorCode, Stage, CATALOGUE};
+use crate::convert::{to_js_or_describe, JsConvert};
+use crate::marshal::{ConversionError, Position, DEFAULT_MAX_DEPTH};
+use crate::host::{call_now, conversion_js_error, fault_error, js_error, settle_promise, Called, Host};
+use crate::vm_core::{self, Breakpoint, Diagnostic, EvalError, Fault, Frame, Interrupt, Location, NativeError, Outcome, State, Vm};
 
 #[wasm_bindgen]
 pub struct WasmVm {
-    interpreter: Interpreter
+    /// Shared so JS functions wrapping script closures can re-enter it.
+    vm: Rc<RefCell<Vm>>,
+    host: Rc<Host>,
+    source: String,
+    natives: Vec<JsNativeFn>,
+    /// Cancellation flag of the latest `run_async`.
+    cancel_async: Rc<Cell<bool>>,
+    /// Kept so `reset` can hand it to the new VM.
+    log_handler: Option<js_sys::Function>
 }
 
+/// Stops a running script at its next safe point; see `WasmVm::interrupt_handle`.
+#[wasm_bindgen]
+pub struct InterruptHandle {
+    interrupt: Interrupt,
+}
+
+#[wasm_bindgen]
+impl InterruptHandle {
+    #[wasm_bindgen]
+    pub fn interrupt(&self) {
+        self.interrupt.raise();
+    }
+}
+
+/// Where in the source the VM is about to execute.
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl From<Location> for SourceLocation {
+    fn from(location: Location) -> Self {
+        return Self {
+            line: location.line,
+            column: location.column,
+            len: location.len,
+        };
+    }
+}
+
+/// One call in `WasmVm::call_stack`.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct StackFrame {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Index of the next instruction in the function's bytecode.
+    pub offset: usize,
+    name: String
+}
+
+#[wasm_bindgen]
+impl StackFrame {
+    /// The function's name; the top-level script has gart's name for it.
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl From<Frame> for StackFrame {
+    fn from(frame: Frame) -> Self {
+        return Self {
+            line: frame.location.map(|location| location.line),
+            column: frame.location.map(|location| location.column),
+            offset: frame.offset,
+            name: frame.name
+        };
+    }
+}
+
+/// A runtime error with the span of the expression that raised it, shaped like
+/// a `CompilerErr`. Errors raised outside the script have no span.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct RuntimeErr {
+    pub line: Option<usize>,
+    pub start: Option<usize>,
+    pub len: Option<usize>,
+    message: String,
+    stack_trace: Vec<StackFrame>,
+    error_code: &'static ErrorCode
+}
+
+#[wasm_bindgen]
+impl RuntimeErr {
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+    /// Stable identifier such as `"E0302"`; see `error_catalogue`.
+    #[wasm_bindgen(getter)]
+    pub fn code(&self) -> String {
+        self.error_code.code.to_owned()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn category(&self) -> String {
+        self.error_code.category.as_str().to_owned()
+    }
+    /// The calls active when the error was raised, innermost first.
+    #[wasm_bindgen(getter)]
+    pub fn stack_trace(&self) -> Vec<StackFrame> {
+        self.stack_trace.clone()
+    }
+}
+
+impl From<&Fault> for RuntimeErr {
+    fn from(fault: &Fault) -> Self {
+        let location = fault.location();
+        return Self {
+            line: location.map(|location| location.line),
+            start: location.map(|location| location.column),
+            len: location.map(|location| location.len),
+            message: fault.message.clone(),
+            stack_trace: fault.stack.iter().cloned().map(StackFrame::from).collect(),
+            error_code: catalogue::for_fault(fault)
+        };
+    }
+}
+
+impl From<&ConversionError> for RuntimeErr {
+    fn from(err: &ConversionError) -> Self {
+        return Self {
+            line: None,
+            start: None,
+            len: None,
+            message: err.to_string(),
+            stack_trace: vec![],
+            error_code: catalogue::for_conversion()
+        };
+    }
+}
+
+/// Milliseconds `run_async` runs for before yielding to the event loop.
+const ASYNC_SLICE_MS: f64 = 8.0;
+
 #[wasm_bindgen]
 pub struct CompilerErr {
     pub line: usize,
     pub start: usize,
     pub len: usize,
-    message: String
+    message: String,
+    error_code: &'static ErrorCode
 }
 
 #[wasm_bindgen]
@@ -23,9 +166,104 @@ impl CompilerErr {
     pub fn message(&self) -> String {
         self.message.clone()
     }
+    /// Stable identifier such as `"E0103"`; see `error_catalogue`.
+    #[wasm_bindgen(getter)]
+    pub fn code(&self) -> String {
+        self.error_code.code.to_owned()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn category(&self) -> String {
+        self.error_code.category.as_str().to_owned()
+    }
+}
+
+impl From<Diagnostic> for CompilerErr {
+    fn from(diagnostic: Diagnostic) -> Self {
+        return CompilerErr {
+            error_code: catalogue::for_diagnostic(&diagnostic),
+            line: diagnostic.line,
+            start: diagnostic.start,
+            len: diagnostic.len,
+            message: diagnostic.message
+        };
+    }
+}
+
+/// One entry of `error_catalogue`.
+#[wasm_bindgen]
+pub struct ErrorInfo {
+    error_code: &'static ErrorCode
+}
+
+#[wasm_bindgen]
+impl ErrorInfo {
+    #[wasm_bindgen(getter)]
+    pub fn code(&self) -> String {
+        self.error_code.code.to_owned()
+    }
+    /// `"syntax"`, `"resolution"`, `"type"`, `"arity"`, `"native"` or `"runtime"`.
+    #[wasm_bindgen(getter)]
+    pub fn category(&self) -> String {
+        self.error_code.category.as_str().to_owned()
+    }
+    /// Whether the error is reported by `compile` rather than while running.
+    #[wasm_bindgen(getter)]
+    pub fn compile_time(&self) -> bool {
+        self.error_code.stage == Stage::Compile
+    }
+    #[wasm_bindgen(getter)]
+    pub fn summary(&self) -> String {
+        self.error_code.summary.to_owned()
+    }
+}
+
+/// Every error code scripts can run into, in code order, for generating docs.
+#[wasm_bindgen]
+pub fn error_catalogue() -> Vec<ErrorInfo> {
+    return CATALOGUE.iter().map(|error_code| ErrorInfo { error_code }).collect();
+}
+
+#[wasm_bindgen]
+pub struct NativeErr {
+    native: String,
+    name: Option<String>,
+    message: String,
+    stack: Option<String>
+}
+
+#[wasm_bindgen]
+impl NativeErr {
+    #[wasm_bindgen(getter)]
+    pub fn native(&self) -> String {
+        self.native.clone()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn stack(&self) -> Option<String> {
+        self.stack.clone()
+    }
+}
+
+impl From<NativeError> for NativeErr {
+    fn from(err: NativeError) -> Self {
+        return NativeErr {
+            native: err.native,
+            name: err.name,
+            message: err.message,
+            stack: err.stack
+        };
+    }
 }
 
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct JsNativeFn {
     name: String,
     arity: u8,
@@ -69,173 +307,876 @@ impl CompileResult {
 }
 
 impl CompileResult {
-    fn new_success(interpreter: Interpreter) -> Self {
+    fn new_success(vm: WasmVm) -> Self {
         Self {
             success: true,
-            vm: Some(WasmVm { interpreter }),
+            vm: Some(vm),
             compile_errors: None,
         }
     }
-    fn new_failure(errors: Vec<CompilerError>) -> Self {
+    fn new_failure(errors: Vec<Diagnostic>) -> Self {
         Self {
             success: false,
             vm: None,
-            compile_errors: Some(errors.into_iter().map(|i| { CompilerErr {
-                line: i.line,
-                start: i.start,
-                len: i.len,
-                message: i.message
-            }}).collect()),
+            compile_errors: Some(errors.into_iter().map(CompilerErr::from).collect()),
         }
     }
 }
 
+/// The result of `WasmVm::evaluate_in_frame`.
+#[wasm_bindgen]
+pub struct EvalResult {
+    success: bool,
+    value: JsValue,
+    compile_errors: Option<Vec<CompilerErr>>,
+    runtime_err: Option<RuntimeErr>
+}
+
+#[wasm_bindgen]
+impl EvalResult {
+    #[wasm_bindgen(getter)]
+    pub fn success(&self) -> bool {
+        self.success
+    }
+    /// The expression's value, described by type if JS cannot represent it;
+    /// `undefined` when evaluation failed.
+    #[wasm_bindgen(getter)]
+    pub fn value(&self) -> JsValue {
+        self.value.clone()
+    }
+    #[wasm_bindgen]
+    pub fn take_compile_errors(&mut self) -> Option<Vec<CompilerErr>> {
+        self.compile_errors.take()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn runtime_error(&self) -> Option<String> {
+        self.runtime_err.as_ref().map(|err| err.message.clone())
+    }
+    /// The runtime error with its code and category.
+    #[wasm_bindgen(getter)]
+    pub fn runtime_err(&self) -> Option<RuntimeErr> {
+        self.runtime_err.clone()
+    }
+}
+
+impl EvalResult {
+    fn evaluated(value: JsValue) -> Self {
+        Self {
+            success: true,
+            value,
+            compile_errors: None,
+            runtime_err: None,
+        }
+    }
+    fn failed(err: EvalError) -> Self {
+        let (compile_errors, runtime_err) = match err {
+            EvalError::Compile(diagnostics) => (Some(diagnostics.into_iter().map(CompilerErr::from).collect()), None),
+            EvalError::Runtime(fault) => (None, Some(RuntimeErr::from(&fault))),
+        };
+        Self {
+            success: false,
+            value: JsValue::UNDEFINED,
+            compile_errors,
+            runtime_err,
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct CallResult {
+    status: Status,
+    success: bool,
+    value: JsValue,
+    runtime_err: Option<RuntimeErr>,
+    pending: Option<Promise>,
+}
+
+#[wasm_bindgen]
+impl CallResult {
+    /// `Finished` once the function has returned, `Errored` if it failed or
+    /// could not be called, and otherwise why it stopped part-way.
+    #[wasm_bindgen(getter)]
+    pub fn status(&self) -> Status {
+        self.status
+    }
+    #[wasm_bindgen(getter)]
+    pub fn success(&self) -> bool {
+        self.success
+    }
+    /// The function's return value; `undefined` when the call failed.
+    #[wasm_bindgen(getter)]
+    pub fn value(&self) -> JsValue {
+        self.value.clone()
+    }
+    /// Only set when `status` is `Errored`.
+    #[wasm_bindgen(getter)]
+    pub fn runtime_error(&self) -> Option<String> {
+        self.runtime_err.as_ref().map(|err| err.message.clone())
+    }
+    /// The error with its code and category; conversion errors have no span.
+    #[wasm_bindgen(getter)]
+    pub fn runtime_err(&self) -> Option<RuntimeErr> {
+        self.runtime_err.clone()
+    }
+    /// For a call that stopped part-way, e.g. at a breakpoint, a promise of its
+    /// result that settles once the VM is resumed and the function returns. A
+    /// call interrupted before it started has none, as it will never run.
+    #[wasm_bindgen(getter)]
+    pub fn pending(&self) -> Option<Promise> {
+        self.pending.clone()
+    }
+}
+
+impl CallResult {
+    fn returned(value: JsValue) -> Self {
+        Self {
+            status: Status::Finished,
+            success: true,
+            value,
+            runtime_err: None,
+            pending: None,
+        }
+    }
+    fn failed(err: RuntimeErr) -> Self {
+        Self {
+            status: Status::Errored,
+            success: false,
+            value: JsValue::UNDEFINED,
+            runtime_err: Some(err),
+            pending: None,
+        }
+    }
+    fn stopped(status: Status, pending: Option<Promise>) -> Self {
+        Self {
+            status,
+            success: false,
+            value: JsValue::UNDEFINED,
+            runtime_err: None,
+            pending,
+        }
+    }
+}
+
+/// Why the VM handed control back; see the matching `Output` getters for payloads.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// Stopped after a single step, with more left to run.
+    Running,
+    Finished,
+    /// See `runtime_error` and `native_error`.
+    Errored,
+    /// A native is awaiting a promise. Only `run_async` carries on by itself;
+    /// otherwise await `WasmVm.resumed` and run the VM again.
+    Suspended,
+    /// See `breakpoint_line`.
+    BreakpointHit,
+    BudgetExhausted,
+    Cancelled,
+}
+
+impl From<&State> for Status {
+    fn from(state: &State) -> Self {
+        return match state {
+            State::Running => Status::Running,
+            State::Finished => Status::Finished,
+            State::Errored(_) => Status::Errored,
+            State::Suspended => Status::Suspended,
+            State::BreakpointHit { .. } => Status::BreakpointHit,
+            State::BudgetExhausted => Status::BudgetExhausted,
+            State::Cancelled => Status::Cancelled,
+        };
+    }
+}
+
 #[wasm_bindgen]
 pub struct Output {
-    finished: bool,
-    runtime_error: Option<String>
+    state: State,
+    value: JsValue,
+    steps: u64,
+    elapsed: Option<f64>,
+    location: Option<Location>
 }
 
 #[wasm_bindgen]
 impl Output {
+    #[wasm_bindgen(getter)]
+    pub fn status(&self) -> Status {
+        Status::from(&self.state)
+    }
+
+    /// Whether the program has stopped for good, successfully or not.
     #[wasm_bindgen(getter)]
     pub fn finished(&self) -> bool {
-        self.finished
+        matches!(self.state, State::Finished | State::Errored(_))
     }
 
     #[wasm_bindgen(getter)]
     pub fn runtime_error(&self) -> Option<String> {
-        self.runtime_error.clone()
+        self.fault().map(|fault| fault.message.clone())
+    }
+    /// The runtime error with its source span and stack trace.
+    #[wasm_bindgen(getter)]
+    pub fn runtime_err(&self) -> Option<RuntimeErr> {
+        self.fault().map(RuntimeErr::from)
+    }
+
+    /// What the host threw, when the runtime error came from a native.
+    #[wasm_bindgen(getter)]
+    pub fn native_error(&self) -> Option<NativeErr> {
+        self.fault().and_then(|fault| fault.native.clone()).map(NativeErr::from)
+    }
+
+    /// Whether a bounded run yielded because it used up its budget.
+    #[wasm_bindgen(getter)]
+    pub fn budget_exhausted(&self) -> bool {
+        self.state == State::BudgetExhausted
+    }
+
+    /// Whether a native is awaiting a promise. Unless the VM is running under
+    /// `run_async`, nothing runs it again: await `WasmVm.resumed`, then call
+    /// `interpret` (or `run_for`, `step`, ...) to carry on with the result.
+    #[wasm_bindgen(getter)]
+    pub fn awaiting(&self) -> bool {
+        self.state == State::Suspended
+    }
+
+    /// The source line of the breakpoint that stopped the run.
+    #[wasm_bindgen(getter)]
+    pub fn breakpoint_line(&self) -> Option<usize> {
+        match self.state {
+            State::BreakpointHit { line } => Some(line),
+            _ => None,
+        }
+    }
+
+    /// What the program finished with; `undefined` until it has finished successfully.
+    #[wasm_bindgen(getter)]
+    pub fn value(&self) -> JsValue {
+        self.value.clone()
+    }
+
+    /// Instructions executed during the call that produced this output.
+    #[wasm_bindgen(getter)]
+    pub fn steps(&self) -> f64 {
+        self.steps as f64
+    }
+
+    /// Milliseconds spent, for `run_for_millis`.
+    #[wasm_bindgen(getter)]
+    pub fn elapsed(&self) -> Option<f64> {
+        self.elapsed
+    }
+
+    /// Source span of the instruction the VM stopped before, like a `CompilerErr`'s.
+    #[wasm_bindgen(getter)]
+    pub fn line(&self) -> Option<usize> {
+        self.location.map(|location| location.line)
+    }
+    #[wasm_bindgen(getter)]
+    pub fn column(&self) -> Option<usize> {
+        self.location.map(|location| location.column)
+    }
+    #[wasm_bindgen(getter = len)]
+    pub fn span_len(&self) -> Option<usize> {
+        self.location.map(|location| location.len)
     }
 }
 
 impl Output {
-    pub fn successful() -> Self {
-        return Self {
-            finished: true,
-            runtime_error: None
-        };
-    }
-    pub fn runtime_err(err: RuntimeError) -> Self {
-        return Self {
-            finished: true,
-            runtime_error: Some(err.message.to_owned())
-        };
+    fn fault(&self) -> Option<&Fault> {
+        match &self.state {
+            State::Errored(fault) => Some(fault),
+            _ => None,
+        }
     }
-    pub fn unfinished() -> Self {
+}
+
+impl From<Outcome> for Output {
+    fn from(outcome: Outcome) -> Self {
         return Self {
-            finished: false,
-            runtime_error: None
+            state: outcome.state,
+            value: JsValue::UNDEFINED,
+            steps: outcome.steps,
+            elapsed: outcome.elapsed,
+            location: None
         };
     }
 }
 
 #[wasm_bindgen]
 pub fn compile(source: &str, natives: Vec<JsNativeFn>) -> CompileResult {
-    let mut rust_natives: Vec::<NativeFunction> = vec![];
-    for native in natives.into_iter() {
-        rust_natives.push(native.into_native());
-    }
-    let time = NativeFunction {
-        name: "time".to_owned(),
-        arity: 0,
-        function: {
-            fn time(_: &[Value]) -> Value {
-                let millis = Date::now();
-                Value::Number(millis / 1000.0)
-            }
-            Box::new(time)
-        },
+    let host = Rc::new(Host::new(DEFAULT_MAX_DEPTH));
+    return match build_vm(source, &natives, &host) {
+        Ok(vm) => CompileResult::new_success(WasmVm {
+            vm,
+            host,
+            source: source.to_owned(),
+            natives,
+            cancel_async: Rc::new(Cell::new(false)),
+            log_handler: None
+        }),
+        Err(diagnostics) => CompileResult::new_failure(diagnostics),
     };
-    rust_natives.push(time);
+}
 
-    return match Interpreter::new(source.to_owned(), rust_natives) {
-        Ok(interpreter) => CompileResult::new_success(interpreter),
-        Err(compiler_errors) => CompileResult::new_failure(compiler_errors),
-    };
+fn build_vm(source: &str, natives: &[JsNativeFn], host: &Rc<Host>) -> Result<Rc<RefCell<Vm>>, Vec<Diagnostic>> {
+    let mut rust_natives: Vec::<NativeFunction> = vec![];
+    for native in natives.iter().cloned() {
+        rust_natives.push(native.into_native(host.clone()));
+    }
+
+    let vm = Rc::new(RefCell::new(vm_core::compile(source, rust_natives, host.link.clone(), Date::now)?));
+    *host.vm.borrow_mut() = Rc::downgrade(&vm);
+    return Ok(vm);
 }
 
 #[wasm_bindgen]
 impl WasmVm {
     #[wasm_bindgen]
     pub fn interpret(&mut self) -> Output {
-        return match self.interpreter.run() {
-            Ok(_) => Output::successful(),
-            Err(runtime_error) => Output::runtime_err(runtime_error),
-        };
+        let outcome = self.vm.borrow_mut().interpret();
+        return self.output(outcome);
     }
     #[wasm_bindgen]
     pub fn step(&mut self) -> Output {
-        return match self.interpreter.step() {
-            Ok(not_finished) => {
-                if not_finished { Output::unfinished() }
-                else { Output::successful() }
+        let outcome = self.vm.borrow_mut().step();
+        return self.output(outcome);
+    }
+    /// The source span of the instruction about to execute, if any.
+    #[wasm_bindgen]
+    pub fn current_location(&self) -> Option<SourceLocation> {
+        return self.vm.borrow().location().map(SourceLocation::from);
+    }
+    /// The active calls, innermost first. After a runtime error, the calls that
+    /// were active when it happened.
+    #[wasm_bindgen]
+    pub fn call_stack(&self) -> Vec<StackFrame> {
+        return self.vm.borrow().call_stack().into_iter().map(StackFrame::from).collect();
+    }
+    /// The local variables in scope in call `frame` of `call_stack` as `[name, value]`
+    /// pairs, in declaration order. Values JS cannot represent, such as functions,
+    /// are described by type instead, e.g. `"<function>"`.
+    ///
+    /// Only works while the program is paused. After a runtime error `call_stack`
+    /// still shows where it happened, but the variables are gone and this throws.
+    #[wasm_bindgen]
+    pub fn frame_locals(&self, frame: usize) -> Result<Array, JsValue> {
+        let locals = self.vm.borrow().frame_locals(frame).map_err(|fault| fault_error(&fault))?;
+        return Ok(self.variables(locals));
+    }
+    /// The variables call `frame` captured from enclosing functions, like `frame_locals`.
+    #[wasm_bindgen]
+    pub fn frame_upvalues(&self, frame: usize) -> Result<Array, JsValue> {
+        let upvalues = self.vm.borrow().frame_upvalues(frame).map_err(|fault| fault_error(&fault))?;
+        return Ok(self.variables(upvalues));
+    }
+    /// Evaluates the expression `source` in the scope of call `frame` of
+    /// `call_stack`, leaving the paused program as it was.
+    #[wasm_bindgen]
+    pub fn evaluate_in_frame(&mut self, frame: usize, source: &str) -> EvalResult {
+        let evaluated = self.vm.borrow_mut().evaluate_in_frame(frame, source);
+        return match evaluated {
+            Ok(value) => EvalResult::evaluated(to_js_or_describe(&value, &mut self.host.marshal())),
+            Err(err) => EvalResult::failed(err),
+        };
+    }
+    /// Runs to the next source line, following execution into calls.
+    #[wasm_bindgen]
+    pub fn step_line(&mut self) -> Output {
+        let outcome = self.vm.borrow_mut().step_line();
+        return self.output(outcome);
+    }
+    /// Runs to the next source line or into the function called on this one.
+    #[wasm_bindgen]
+    pub fn step_into(&mut self) -> Output {
+        let outcome = self.vm.borrow_mut().step_into();
+        return self.output(outcome);
+    }
+    /// Runs to the next source line in this function, running calls made from
+    /// it to completion unless they hit a breakpoint.
+    #[wasm_bindgen]
+    pub fn step_over(&mut self) -> Output {
+        let outcome = self.vm.borrow_mut().step_over();
+        return self.output(outcome);
+    }
+    /// Runs until the current function returns to its caller.
+    #[wasm_bindgen]
+    pub fn step_out(&mut self) -> Output {
+        let outcome = self.vm.borrow_mut().step_out();
+        return self.output(outcome);
+    }
+    /// Executes up to `max_steps` instructions without leaving wasm, so long
+    /// scripts can be run in slices between animation frames.
+    #[wasm_bindgen]
+    pub fn run_for(&mut self, max_steps: u32) -> Output {
+        let outcome = self.vm.borrow_mut().run_for(u64::from(max_steps));
+        return self.output(outcome);
+    }
+    /// Runs until the program finishes or roughly `ms` milliseconds have passed.
+    #[wasm_bindgen]
+    pub fn run_for_millis(&mut self, ms: f64) -> Output {
+        let outcome = self.vm.borrow_mut().run_for_millis(ms, now);
+        return self.output(outcome);
+    }
+    /// Runs the program in short slices, yielding to the event loop between them.
+    ///
+    /// Resolves with the final `Output` or the one for a breakpoint, or rejects with an `Error` on a runtime
+    /// error, when `cancel_async` is called, when another `run_async` starts or when the VM is interrupted.
+    /// Runtime errors carry the `code` and `category` their `RuntimeErr` would have.
+    #[wasm_bindgen]
+    pub fn run_async(&mut self) -> Promise {
+        let vm = self.vm.clone();
+        let host = self.host.clone();
+        // A run already in flight would otherwise keep driving the same VM.
+        self.cancel_async.set(true);
+        let cancelled = Rc::new(Cell::new(false));
+        self.cancel_async = cancelled.clone();
+
+        return Promise::new(&mut |resolve, reject| {
+            AsyncRun { vm: vm.clone(), host: host.clone(), cancelled: cancelled.clone(), resolve, reject }.slice();
+        });
+    }
+    /// While a native is awaiting a promise, a promise that resolves once it has
+    /// settled either way and the VM can be run again to continue.
+    #[wasm_bindgen(getter)]
+    pub fn resumed(&self) -> Option<Promise> {
+        resumed(&self.host)
+    }
+    /// A handle whose `interrupt()` makes the current or next run stop with
+    /// `Status.Cancelled`. The VM stays resumable, and the handle keeps working
+    /// across `reset`.
+    #[wasm_bindgen]
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        return InterruptHandle { interrupt: self.host.link.interrupt.clone() };
+    }
+    /// Also treats a non-zero first element of `buffer` as an interrupt request,
+    /// clearing it. Backed by a `SharedArrayBuffer`, this lets the page stop a
+    /// script running in a worker that is too busy to receive messages.
+    #[wasm_bindgen]
+    pub fn set_interrupt_buffer(&mut self, buffer: Int32Array) {
+        self.host.link.interrupt.set_probe(move || {
+            Atomics::exchange(&buffer, 0, 0).is_ok_and(|previous| previous != 0)
+        });
+    }
+    /// Stops the promise returned by the latest `run_async` at its next yield.
+    /// The VM is left where it stopped and can be resumed.
+    #[wasm_bindgen]
+    pub fn cancel_async(&mut self) {
+        self.cancel_async.set(true);
+    }
+
+    /// Makes `interpret`, `run_for` and `run_async` stop with `Status.BreakpointHit`
+    /// whenever execution moves onto `line`.
+    #[wasm_bindgen]
+    pub fn set_breakpoint(&mut self, line: usize) {
+        self.vm.borrow_mut().set_breakpoint(line, Breakpoint::default());
+    }
+    /// Like `set_breakpoint`, but only stops once `condition`, a script expression
+    /// evaluated where the breakpoint is, is truthy and the breakpoint has been
+    /// hit `hit_count` times.
+    #[wasm_bindgen]
+    pub fn set_conditional_breakpoint(&mut self, line: usize, condition: Option<String>, hit_count: Option<u32>) {
+        let breakpoint = Breakpoint {
+            condition,
+            hit_count: hit_count.map(u64::from),
+            log_message: None,
+        };
+        self.vm.borrow_mut().set_breakpoint(line, breakpoint);
+    }
+    /// Sends `message` to the log handler whenever execution moves onto `line`,
+    /// without stopping. `{expression}` parts are replaced by their values.
+    #[wasm_bindgen]
+    pub fn set_logpoint(&mut self, line: usize, message: String, condition: Option<String>) {
+        let breakpoint = Breakpoint {
+            condition,
+            hit_count: None,
+            log_message: Some(message),
+        };
+        self.vm.borrow_mut().set_breakpoint(line, breakpoint);
+    }
+    /// Calls `handler(message, line)` for each logpoint message.
+    #[wasm_bindgen]
+    pub fn set_log_handler(&mut self, handler: js_sys::Function) {
+        install_log_handler(&self.vm, handler.clone());
+        self.log_handler = Some(handler);
+    }
+    #[wasm_bindgen]
+    pub fn clear_breakpoint(&mut self, line: usize) {
+        self.vm.borrow_mut().clear_breakpoint(line);
+    }
+    #[wasm_bindgen]
+    pub fn clear_all_breakpoints(&mut self) {
+        self.vm.borrow_mut().clear_all_breakpoints();
+    }
+
+    /// Calls the script's global function `name` once the top-level script has finished.
+    /// If the function stops part-way, e.g. at a breakpoint, the result's `status`
+    /// says why and its `pending` promise settles once the function returns.
+    #[wasm_bindgen]
+    pub fn call(&mut self, name: &str, args: Array) -> CallResult {
+        let mut cx = self.host.marshal();
+        let mut gart_args = Vec::with_capacity(args.length() as usize);
+        for (index, arg) in args.iter().enumerate() {
+            match Value::try_from_js(arg, &mut cx) {
+                Ok(arg) => gart_args.push(arg),
+                Err(err) => return CallResult::failed(RuntimeErr::from(&err.at(Position::Argument(index)))),
+            }
+        }
+
+        let callee = match self.vm.borrow().function(name) {
+            Ok(callee) => callee,
+            Err(fault) => return CallResult::failed(RuntimeErr::from(&fault)),
+        };
+        let called = call_now(&self.host, &mut self.vm.borrow_mut(), callee, gart_args);
+        return match called {
+            Ok(Called::Returned(Ok(value))) => match value.try_to_js(&mut cx) {
+                Ok(value) => CallResult::returned(value),
+                Err(err) => CallResult::failed(RuntimeErr::from(&err.at(Position::ReturnValue))),
             },
-            Err(runtime_error) => Output::runtime_err(runtime_error),
+            Ok(Called::Returned(Err(fault))) | Err(fault) => CallResult::failed(RuntimeErr::from(&fault)),
+            Ok(Called::Cancelled(_)) => CallResult::stopped(Status::Cancelled, None),
+            Ok(Called::Stopped(outcome, promise)) => CallResult::stopped(Status::from(&outcome.state), Some(promise)),
+        };
+    }
+
+    #[wasm_bindgen]
+    pub fn get_global(&self, name: &str) -> Result<JsValue, JsValue> {
+        let value = self.vm.borrow().global(name).map_err(|fault| fault_error(&fault))?;
+        return value.try_to_js(&mut self.host.marshal()).map_err(|err| conversion_js_error(&err));
+    }
+
+    /// Assigns a global the script has defined; throws for unknown names.
+    #[wasm_bindgen]
+    pub fn set_global(&mut self, name: &str, value: JsValue) -> Result<(), JsValue> {
+        let value = Value::try_from_js(value, &mut self.host.marshal()).map_err(|err| conversion_js_error(&err))?;
+        return self.vm.borrow_mut().set_global(name, value).map_err(|fault| fault_error(&fault));
+    }
+
+    /// Assigns a global, creating it if the script never defined it.
+    #[wasm_bindgen]
+    pub fn define_global(&mut self, name: &str, value: JsValue) -> Result<(), JsValue> {
+        let value = Value::try_from_js(value, &mut self.host.marshal()).map_err(|err| conversion_js_error(&err))?;
+        self.vm.borrow_mut().define_global(name, value);
+        return Ok(());
+    }
+
+    /// A snapshot of the script's globals as a plain object, in definition order.
+    /// Globals JS cannot represent, such as natives, are left out.
+    #[wasm_bindgen]
+    pub fn globals(&self) -> Object {
+        let mut cx = self.host.marshal();
+        let pairs = Array::new();
+        for (name, value) in self.vm.borrow().globals() {
+            if let Ok(value) = value.try_to_js(&mut cx) {
+                pairs.push(&Array::of2(&JsValue::from_str(&name), &value));
+            }
+        }
+        return Object::from_entries(&pairs).unwrap_or_default();
+    }
+
+    /// Whether a runtime error has stopped the VM; it stays stopped until `reset`.
+    #[wasm_bindgen(getter)]
+    pub fn errored(&self) -> bool {
+        self.vm.borrow().error().is_some()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn runtime_error(&self) -> Option<String> {
+        self.vm.borrow().error().map(|fault| fault.message.clone())
+    }
+    #[wasm_bindgen(getter)]
+    pub fn runtime_err(&self) -> Option<RuntimeErr> {
+        self.vm.borrow().error().map(RuntimeErr::from)
+    }
+
+    /// Recompiles the script with the same natives, discarding all state but
+    /// breakpoints and the log handler.
+    #[wasm_bindgen]
+    pub fn reset(&mut self) {
+        self.cancel_async.set(true);
+        let mut host = Host::new(self.host.max_depth.get());
+        host.link.interrupt = self.host.link.interrupt.clone();
+        let host = Rc::new(host);
+        if let Ok(vm) = build_vm(&self.source, &self.natives, &host) {
+            for (line, breakpoint) in self.vm.borrow().breakpoints() {
+                vm.borrow_mut().set_breakpoint(line, breakpoint.clone());
+            }
+            if let Some(handler) = self.log_handler.clone() {
+                install_log_handler(&vm, handler);
+            }
+            self.vm.borrow_mut().abandon_call();
+            self.vm = vm;
+            self.host = host;
+        }
+    }
+
+    /// How deeply nested lists and maps may be when passed to or from natives.
+    #[wasm_bindgen(getter)]
+    pub fn max_conversion_depth(&self) -> usize {
+        self.host.max_depth.get()
+    }
+    #[wasm_bindgen(setter)]
+    pub fn set_max_conversion_depth(&mut self, depth: usize) {
+        self.host.max_depth.set(depth);
+    }
+
+    /// Lets go of `object` if scripts hold it as a handle, returning whether
+    /// they did. Scripts that still hold the handle can no longer pass it to JS.
+    #[wasm_bindgen]
+    pub fn release_handle(&self, object: JsValue) -> bool {
+        self.host.release_handle(&object)
+    }
+}
+
+/// Milliseconds from `performance.now()`, or `Date.now()` where there is no `performance`.
+fn now() -> f64 {
+    let performance = Reflect::get(&js_sys::global(), &JsValue::from_str("performance"))
+        .ok()
+        .filter(|performance| performance.is_object());
+    if let Some(performance) = performance {
+        let now = Reflect::get(&performance, &JsValue::from_str("now"))
+            .ok()
+            .and_then(|now| now.dyn_into::<js_sys::Function>().ok());
+        if let Some(time) = now.and_then(|now| now.call0(&performance).ok()).and_then(|time| time.as_f64()) {
+            return time;
+        }
+    }
+    return Date::now();
+}
+
+/// Resolves on a later macrotask, giving the page a chance to render and handle input.
+fn yield_to_event_loop() -> Promise {
+    return Promise::new(&mut |resolve, _| {
+        let set_timeout = Reflect::get(&js_sys::global(), &JsValue::from_str("setTimeout"))
+            .ok()
+            .and_then(|set_timeout| set_timeout.dyn_into::<js_sys::Function>().ok());
+        let scheduled = set_timeout.and_then(|set_timeout| set_timeout.call2(&JsValue::NULL, &resolve, &JsValue::from(0)).ok());
+        if scheduled.is_none() {
+            let _ = resolve.call0(&JsValue::NULL);
+        }
+    });
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// `promise.then(callback)`, taking a plain function so one-shot closures can be passed.
+    #[wasm_bindgen(method, js_name = then)]
+    fn then_call(promise: &Promise, callback: &js_sys::Function) -> Promise;
+}
+
+/// Calls `callback` with the value `promise` fulfils with; a rejection is left unhandled.
+fn then(promise: &Promise, callback: impl FnOnce(JsValue) + 'static) {
+    then_call(promise, Closure::once_into_js(callback).unchecked_ref());
+}
+
+/// Calls `callback` once `promise` has settled, with its `{ status, value, reason }` record.
+fn when_settled(promise: &Promise, callback: impl FnOnce(JsValue) + 'static) {
+    then(&Promise::all_settled(&Array::of1(promise)), move |settled| {
+        callback(settled.unchecked_into::<Array>().get(0));
+    });
+}
+
+/// A `run_async` in progress, which runs one slice per turn of the event loop.
+struct AsyncRun {
+    vm: Rc<RefCell<Vm>>,
+    host: Rc<Host>,
+    cancelled: Rc<Cell<bool>>,
+    resolve: js_sys::Function,
+    reject: js_sys::Function,
+}
+
+impl AsyncRun {
+    /// Runs one slice, then settles the run's promise or schedules the next slice.
+    fn slice(self) {
+        if self.cancelled.get() {
+            return self.settle(Err(js_error("Cancelled.")));
+        }
+        let outcome = self.vm.borrow_mut().run_for_millis(ASYNC_SLICE_MS, now);
+        if let Some(fault) = outcome.error() {
+            return self.settle(Err(fault_error(fault)));
+        }
+        if outcome.state == State::Cancelled {
+            return self.settle(Err(js_error("Cancelled.")));
+        }
+        if outcome.finished() || matches!(outcome.state, State::BreakpointHit { .. }) {
+            let output = output(&self.vm, &self.host, outcome);
+            return self.settle(Ok(output.into()));
+        }
+
+        // A suspended VM continues once the awaited promise settles; otherwise
+        // the next slice waits a macrotask so the page can render. Neither
+        // promise rejects.
+        let resumed = match outcome.state {
+            State::Suspended => resumed(&self.host),
+            _ => None,
         };
+        let next = resumed.unwrap_or_else(yield_to_event_loop);
+        then(&next, move |_| self.slice());
+    }
+
+    fn settle(self, settled: Result<JsValue, JsValue>) {
+        settle_promise(settled, &self.resolve, &self.reject);
     }
 }
 
-pub trait IntoNative { 
-    fn into_native(self) -> NativeFunction; 
+fn install_log_handler(vm: &RefCell<Vm>, handler: js_sys::Function) {
+    vm.borrow_mut().set_log_handler(move |line, message| {
+        // A throwing handler loses its message but must not stop the script.
+        let _ = handler.call2(&JsValue::NULL, &JsValue::from_str(message), &JsValue::from(line));
+    });
+}
+
+fn resumed(host: &Host) -> Option<Promise> {
+    if !host.link.suspension.is_pending() {
+        return None;
+    }
+    let awaiting = host.awaiting.borrow().clone()?;
+    return Some(Promise::all_settled(&Array::of1(&awaiting)));
+}
+
+fn output(vm: &RefCell<Vm>, host: &Rc<Host>, outcome: Outcome) -> Output {
+    let mut output = Output::from(outcome);
+    output.location = vm.borrow().location();
+    if let Some(value) = vm.borrow().completion() {
+        output.value = to_js_or_describe(&value, &mut host.marshal());
+    }
+    return output;
+}
+
+impl WasmVm {
+    fn output(&self, outcome: Outcome) -> Output {
+        return output(&self.vm, &self.host, outcome);
+    }
+
+    fn variables(&self, variables: Vec<(String, Value)>) -> Array {
+        let mut cx = self.host.marshal();
+        let pairs = Array::new();
+        for (name, value) in variables {
+            pairs.push(&Array::of2(&JsValue::from_str(&name), &to_js_or_describe(&value, &mut cx)));
+        }
+        return pairs;
+    }
 }
 
-pub trait JsConvert { 
-    fn to_js(&self) -> JsValue; 
-    fn from_js(js: JsValue) -> Self; 
+pub trait IntoNative { 
+    fn into_native(self, host: Rc<Host>) -> NativeFunction; 
 }
 
 impl IntoNative for JsNativeFn {
-    fn into_native(self) -> NativeFunction {
+    fn into_native(self, host: Rc<Host>) -> NativeFunction {
         let js_func = self.function;
+        let name = self.name.clone();
 
         NativeFunction {
             name: self.name,
             arity: self.arity,
             function: Box::new(move |vals: &[Value]| {
-
-                let js_args = vals.iter()
-                    .map(|v| v.to_js())
-                    .collect::<Vec<_>>();
-
+                let native_errors = &host.link.native_errors;
+                let mut cx = host.marshal();
                 let array = Array::new();
-                for arg in js_args {
-                    array.push(&arg);
+                for (index, val) in vals.iter().enumerate() {
+                    match val.try_to_js(&mut cx) {
+                        Ok(arg) => { array.push(&arg); },
+                        Err(err) => {
+                            native_errors.raise(conversion_error(&name, err.at(Position::Argument(index))));
+                            return Value::Null;
+                        }
+                    }
                 }
 
-                let result = js_func
-                    .apply(&JsValue::NULL, &array)
-                    .expect("JS function threw");
-
-                Value::from_js(result)
+                let result = match js_func.apply(&JsValue::NULL, &array) {
+                    Ok(result) if result.is_instance_of::<Promise>() => {
+                        suspend_on(&host, &name, result.unchecked_into());
+                        return Value::Null;
+                    },
+                    Ok(result) => Value::try_from_js(result, &mut cx),
+                    Err(thrown) => {
+                        native_errors.raise(native_error(&name, thrown));
+                        return Value::Null;
+                    }
+                };
+                match result {
+                    Ok(value) => value,
+                    Err(err) => {
+                        native_errors.raise(conversion_error(&name, err.at(Position::ReturnValue)));
+                        Value::Null
+                    }
+                }
             }),
         }
     }
 }
 
-impl JsConvert for Value {
-    fn to_js(&self) -> JsValue {
-        match self {
-            Value::Number(n) => JsValue::from_f64(*n),
-            Value::Bool(b) => JsValue::from_bool(*b),
-            Value::String(s) => JsValue::from_str(s),
-            Value::Null => JsValue::NULL,
-            _ => panic!("Unsupported value.")
-        }
-    }
+/// Suspends the VM until `promise` settles, then hands it the resolved value
+/// as the native's result, or fails it with the rejection. Does nothing where
+/// the VM cannot wait, e.g. in a watch expression, which then fails instead.
+fn suspend_on(host: &Rc<Host>, native: &str, promise: Promise) {
+    let Some(ticket) = host.link.suspension.suspend() else {
+        return;
+    };
+    *host.awaiting.borrow_mut() = Some(promise.clone());
+    let host = Rc::downgrade(host);
+    let native = native.to_owned();
 
-    fn from_js(js: JsValue) -> Self {
-        if js.is_null() || js.is_undefined() {
-            Value::Null
-        } else if let Some(n) = js.as_f64() {
-            Value::Number(n)
-        } else if let Some(b) = js.as_bool() {
-            Value::Bool(b)
-        } else if let Some(s) = js.as_string() {
-            Value::String(s.into())
-        } else {
-            panic!("Unsupported JS value")
-        }
+    when_settled(&promise, move |settled| {
+        let Some(host) = host.upgrade() else { return };
+        let field = |name: &str| Reflect::get(&settled, &JsValue::from_str(name)).unwrap_or(JsValue::UNDEFINED);
+        let result = match field("status").as_string().as_deref() {
+            Some("fulfilled") => Value::try_from_js(field("value"), &mut host.marshal())
+                .map_err(|err| Fault::from(conversion_error(&native, err.at(Position::ReturnValue)))),
+            _ => Err(Fault::from(native_error(&native, field("reason")))),
+        };
+        host.link.suspension.settle(ticket, result);
+    });
+}
+
+fn native_error(native: &str, thrown: JsValue) -> NativeError {
+    let stack = Reflect::get(&thrown, &JsValue::from_str("stack"))
+        .ok()
+        .and_then(|stack| stack.as_string());
+
+    return match thrown.dyn_ref::<js_sys::Error>() {
+        Some(error) => NativeError {
+            native: native.to_owned(),
+            name: Some(error.name().into()),
+            message: error.message().into(),
+            stack
+        },
+        None => NativeError {
+            native: native.to_owned(),
+            name: None,
+            message: describe_thrown(&thrown),
+            stack
+        },
+    };
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = String, catch)]
+    fn js_string(value: &JsValue) -> Result<String, JsValue>;
+}
+
+/// Text for a thrown value that is not an `Error`, e.g. `throw 42` or `throw { code: 1 }`.
+fn describe_thrown(thrown: &JsValue) -> String {
+    if let Some(message) = thrown.as_string() {
+        return message;
+    }
+    // `String({ code: 1 })` is just "[object Object]", so records are shown as JSON.
+    if thrown.is_object()
+        && let Some(json) = js_sys::JSON::stringify(thrown).ok().and_then(|json| json.as_string())
+    {
+        return json;
     }
+    // `String` only throws for objects without a usable `toString`, such as `Object.create(null)`.
+    return js_string(thrown).unwrap_or_else(|_| "a value that cannot be printed".to_owned());
 }
 
+fn conversion_error(native: &str, err: ConversionError) -> NativeError {
+    return NativeError {
+        native: native.to_owned(),
+        name: Some("ConversionError".to_owned()),
+        message: err.to_string(),
+        stack: None
+    };
+}